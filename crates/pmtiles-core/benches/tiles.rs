@@ -0,0 +1,68 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pmtiles_core::cache::{Cache, InMemoryCache, LruCache};
+use pmtiles_core::compress::{decompress, Compression};
+use pmtiles_core::fetcher::{Fetcher, LocalFetcher};
+use pmtiles_core::{get_raw_tile, get_tile};
+
+const ARCHIVE: &str = "../../testdata/data/data.pmtiles";
+// The largest tile of the test archive, 78408 bytes uncompressed
+const TILE: (u64, u64, u64) = (14, 9325, 4732);
+
+fn archive_path() -> String {
+    std::path::Path::new(ARCHIVE)
+        .canonicalize()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .into()
+}
+
+fn bench_cache(c: &mut Criterion) {
+    let data = Bytes::from(vec![7u8; 78408]);
+    let in_memory = InMemoryCache::new();
+    in_memory.set("tile", data.clone()).unwrap();
+    let lru = LruCache::new(1 << 20, None);
+    lru.set("tile", data.clone()).unwrap();
+
+    c.bench_function("in_memory_cache_get", |b| {
+        b.iter(|| black_box(in_memory.get(black_box("tile"))))
+    });
+    c.bench_function("lru_cache_get", |b| {
+        b.iter(|| black_box(lru.get(black_box("tile"))))
+    });
+    c.bench_function("decompress_none", |b| {
+        b.iter(|| black_box(decompress(data.clone(), Compression::None).unwrap()))
+    });
+}
+
+// Read the test tile with a warm header cache, so only the tile itself is read
+fn bench_fetcher<F: Fetcher>(c: &mut Criterion, name: &str, client: F) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let path = archive_path();
+    let cache = InMemoryCache::new();
+    let (z, x, y) = TILE;
+    runtime
+        .block_on(get_tile(z, x, y, &path, &client, Some(&cache)))
+        .unwrap();
+
+    c.bench_function(&format!("{}_get_raw_tile", name), |b| {
+        b.to_async(&runtime)
+            .iter(|| async { black_box(get_raw_tile(z, x, y, &path, &client, Some(&cache)).await) })
+    });
+    c.bench_function(&format!("{}_get_tile", name), |b| {
+        b.to_async(&runtime)
+            .iter(|| async { black_box(get_tile(z, x, y, &path, &client, Some(&cache)).await) })
+    });
+}
+
+fn bench_tiles(c: &mut Criterion) {
+    bench_fetcher(c, "local", LocalFetcher::new());
+    #[cfg(feature = "mmap")]
+    bench_fetcher(c, "mmap", pmtiles_core::fetcher::MmapFetcher::new());
+}
+
+criterion_group!(benches, bench_cache, bench_tiles);
+criterion_main!(benches);
@@ -1,6 +1,18 @@
+use crate::helpers::{decode_entries, encode_entries, log_cache_set};
+use crate::models::Directory;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 use fxhash::FxHashMap as HashMap;
-use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::runtime::RuntimeFlavor;
+use xxhash_rust::xxh3::xxh3_64;
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -8,34 +20,1049 @@ pub enum CacheError {
     SetError(String),
 }
 
+// Check whether a key is the given key or one derived from it, e.g. {path}|metadata
+fn is_derived_key(candidate: &str, key: &str) -> bool {
+    candidate
+        .strip_prefix(key)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('|'))
+}
+
+// Cached data is reference counted, so reading it from an in-memory cache does not copy it
 pub trait Cache {
-    fn get(&self, key: &str) -> Option<Vec<u8>>;
-    fn set(&self, key: &str, data: &[u8]) -> Result<(), CacheError>;
+    fn get(&self, key: &str) -> Option<Bytes>;
+    fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError>;
+    // Remove the entry of the key and all entries of keys derived from it
+    fn invalidate(&self, key: &str) -> Result<(), CacheError>;
+
+    // Decoded directories are stored as uncompressed entry bytes by default.
+    // Caches that keep values in memory override these to skip the decoding.
+    fn get_directory(&self, key: &str) -> Option<Arc<Directory>> {
+        let data = self.get(key)?;
+        match decode_entries(&data) {
+            Ok(entries) => Some(Arc::new(entries.into())),
+            Err(err) => {
+                tracing::warn!("failed to decode cached directory {}: {}", key, err);
+                None
+            }
+        }
+    }
+    fn set_directory(&self, key: &str, directory: Arc<Directory>) -> Result<(), CacheError> {
+        self.set(key, encode_entries(&directory.entries).into())
+    }
+
+    // Tile contents are only cached by caches given a tile cache of their own. The zoom
+    // level of the tile is passed along, so that tiles read from one cache can be stored
+    // in another.
+    fn get_tile(&self, _key: &str, _z: u8) -> Option<Bytes> {
+        None
+    }
+    fn set_tile(&self, _key: &str, _z: u8, _data: Bytes) -> Result<(), CacheError> {
+        Ok(())
+    }
+}
+
+// Split a tile key of the form {path}|tile|{tile_id}|{etag} into its path and ETag
+fn split_tile_key(key: &str) -> Option<(&str, &str)> {
+    let (path, rest) = key.split_once("|tile|")?;
+    let (_, etag) = rest.split_once('|')?;
+    Some((path, etag))
+}
+
+#[derive(Default)]
+struct PinnedTiles {
+    tiles: HashMap<String, Bytes>,
+    // ETag of the archive the pinned tiles of each path were read from
+    etags: HashMap<String, String>,
+}
+
+// Tile contents, kept apart from other cached data so that tiles have a size budget of
+// their own. Tiles up to pin_max_zoom are kept until their archive changes, as the few
+// low zoom tiles are the ones requested the most. Pinning a tile of a replaced archive
+// drops the pinned tiles of the previous version.
+pub struct TileCache {
+    tiles: LruCache,
+    pinned: Mutex<PinnedTiles>,
+    pin_max_zoom: Option<u8>,
+}
+
+impl TileCache {
+    pub fn new(max_bytes: usize, pin_max_zoom: Option<u8>) -> Self {
+        TileCache {
+            tiles: LruCache::new(max_bytes, None),
+            pinned: Mutex::new(PinnedTiles::default()),
+            pin_max_zoom,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let pinned = self.pinned.lock().ok()?.tiles.get(key).cloned();
+        pinned.or_else(|| self.tiles.get(key))
+    }
+
+    pub fn set(&self, key: &str, z: u8, data: Bytes) -> Result<(), CacheError> {
+        if self.pin_max_zoom.is_some_and(|max_zoom| z <= max_zoom) {
+            let mut pinned = self
+                .pinned
+                .lock()
+                .map_err(|err| CacheError::SetError(err.to_string()))?;
+            if let Some((path, etag)) = split_tile_key(key) {
+                if pinned
+                    .etags
+                    .get(path)
+                    .is_some_and(|pinned_etag| pinned_etag != etag)
+                {
+                    let prefix = format!("{}|tile|", path);
+                    pinned
+                        .tiles
+                        .retain(|candidate, _| !candidate.starts_with(&prefix));
+                }
+                pinned.etags.insert(path.into(), etag.into());
+            }
+            pinned.tiles.insert(key.into(), data);
+            return Ok(());
+        }
+        self.tiles.set(key, data)
+    }
+
+    // Remove the tile of the key and all tiles of keys derived from it
+    pub fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+        let mut pinned = self
+            .pinned
+            .lock()
+            .map_err(|err| CacheError::SetError(err.to_string()))?;
+        pinned
+            .tiles
+            .retain(|candidate, _| !is_derived_key(candidate, key));
+        pinned.etags.retain(|path, _| !is_derived_key(path, key));
+        drop(pinned);
+        self.tiles.invalidate(key)
+    }
 }
 
 pub struct InMemoryCache {
-    cache: Mutex<HashMap<String, Vec<u8>>>,
+    cache: Mutex<HashMap<String, Bytes>>,
+    directories: Mutex<HashMap<String, Arc<Directory>>>,
+    tiles: Option<TileCache>,
+}
+
+impl Default for InMemoryCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InMemoryCache {
     pub fn new() -> Self {
         InMemoryCache {
             cache: Mutex::new(HashMap::default()),
+            directories: Mutex::new(HashMap::default()),
+            tiles: None,
         }
     }
+
+    pub fn with_tiles(mut self, tiles: TileCache) -> Self {
+        self.tiles = Some(tiles);
+        self
+    }
 }
 
 impl Cache for InMemoryCache {
-    fn get(&self, key: &str) -> Option<Vec<u8>> {
+    fn get(&self, key: &str) -> Option<Bytes> {
         match &self.cache.lock() {
             Ok(cache) => cache.get(key).cloned(),
             Err(_) => None,
         }
     }
 
-    fn set(&self, key: &str, data: &[u8]) -> Result<(), CacheError> {
+    fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(key.into(), data);
+        Ok(())
+    }
+
+    fn invalidate(&self, key: &str) -> Result<(), CacheError> {
         let mut cache = self.cache.lock().unwrap();
-        cache.insert(key.into(), data.into());
+        cache.retain(|candidate, _| !is_derived_key(candidate, key));
+        let mut directories = self.directories.lock().unwrap();
+        directories.retain(|candidate, _| !is_derived_key(candidate, key));
+        if let Some(tiles) = &self.tiles {
+            tiles.invalidate(key)?;
+        }
+        Ok(())
+    }
+
+    fn get_directory(&self, key: &str) -> Option<Arc<Directory>> {
+        match &self.directories.lock() {
+            Ok(directories) => directories.get(key).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    fn set_directory(&self, key: &str, directory: Arc<Directory>) -> Result<(), CacheError> {
+        let mut directories = self.directories.lock().unwrap();
+        directories.insert(key.into(), directory);
+        Ok(())
+    }
+
+    fn get_tile(&self, key: &str, _z: u8) -> Option<Bytes> {
+        self.tiles.as_ref()?.get(key)
+    }
+
+    fn set_tile(&self, key: &str, z: u8, data: Bytes) -> Result<(), CacheError> {
+        match &self.tiles {
+            Some(tiles) => tiles.set(key, z, data),
+            None => Ok(()),
+        }
+    }
+}
+
+enum LruValue {
+    Bytes(Bytes),
+    Directory(Arc<Directory>),
+}
+
+impl LruValue {
+    fn size(&self) -> usize {
+        match self {
+            LruValue::Bytes(data) => data.len(),
+            LruValue::Directory(directory) => {
+                directory.entries.len() * std::mem::size_of::<crate::models::TileEntry>()
+            }
+        }
+    }
+}
+
+struct LruEntry {
+    value: LruValue,
+    tick: u64,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<String, LruEntry>,
+    // Keys by the tick of their latest use, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+}
+
+impl LruState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.size -= key.len() + entry.value.size();
+        }
+    }
+}
+
+// Cache holding at most max_bytes of keys and data, evicting the least recently used
+// entries first. Entries optionally expire after the given time to live.
+pub struct LruCache {
+    state: Mutex<LruState>,
+    max_bytes: usize,
+    ttl: Option<Duration>,
+    tiles: Option<Box<TileCache>>,
+}
+
+impl LruCache {
+    pub fn new(max_bytes: usize, ttl: Option<Duration>) -> Self {
+        LruCache {
+            state: Mutex::new(LruState::default()),
+            max_bytes,
+            ttl,
+            tiles: None,
+        }
+    }
+
+    pub fn with_tiles(mut self, tiles: TileCache) -> Self {
+        self.tiles = Some(Box::new(tiles));
+        self
+    }
+
+    // Total size of the cached keys and data in bytes
+    pub fn size(&self) -> usize {
+        self.state.lock().map(|state| state.size).unwrap_or(0)
+    }
+
+    fn get_value<T>(&self, key: &str, read: impl FnOnce(&LruValue) -> Option<T>) -> Option<T> {
+        let mut state = self.state.lock().ok()?;
+        let entry = state.entries.get(key)?;
+        if entry
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+        {
+            state.remove(key);
+            return None;
+        }
+        let old_tick = entry.tick;
+        let tick = state.next_tick();
+        state.order.remove(&old_tick);
+        state.order.insert(tick, key.into());
+        let entry = state.entries.get_mut(key)?;
+        entry.tick = tick;
+        read(&entry.value)
+    }
+
+    fn set_value(&self, key: &str, value: LruValue) -> Result<(), CacheError> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|err| CacheError::SetError(err.to_string()))?;
+        state.remove(key);
+        let entry_size = key.len() + value.size();
+        if entry_size > self.max_bytes {
+            tracing::debug!("not caching key {} larger than the cache", key);
+            return Ok(());
+        }
+        while state.size + entry_size > self.max_bytes {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = state.entries.remove(&oldest) {
+                state.size -= oldest.len() + entry.value.size();
+            }
+        }
+        let tick = state.next_tick();
+        state.order.insert(tick, key.into());
+        state.entries.insert(
+            key.into(),
+            LruEntry {
+                value,
+                tick,
+                expires_at: self.ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+        state.size += entry_size;
         Ok(())
     }
 }
+
+impl Cache for LruCache {
+    fn get(&self, key: &str) -> Option<Bytes> {
+        self.get_value(key, |value| match value {
+            LruValue::Bytes(data) => Some(data.clone()),
+            LruValue::Directory(_) => None,
+        })
+    }
+
+    fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError> {
+        self.set_value(key, LruValue::Bytes(data))
+    }
+
+    fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|err| CacheError::SetError(err.to_string()))?;
+        let keys: Vec<String> = state
+            .entries
+            .keys()
+            .filter(|candidate| is_derived_key(candidate, key))
+            .cloned()
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+        drop(state);
+        if let Some(tiles) = &self.tiles {
+            tiles.invalidate(key)?;
+        }
+        Ok(())
+    }
+
+    fn get_directory(&self, key: &str) -> Option<Arc<Directory>> {
+        self.get_value(key, |value| match value {
+            LruValue::Directory(directory) => Some(directory.clone()),
+            LruValue::Bytes(_) => None,
+        })
+    }
+
+    fn set_directory(&self, key: &str, directory: Arc<Directory>) -> Result<(), CacheError> {
+        self.set_value(key, LruValue::Directory(directory))
+    }
+
+    fn get_tile(&self, key: &str, _z: u8) -> Option<Bytes> {
+        self.tiles.as_ref()?.get(key)
+    }
+
+    fn set_tile(&self, key: &str, z: u8, data: Bytes) -> Result<(), CacheError> {
+        match &self.tiles {
+            Some(tiles) => tiles.set(key, z, data),
+            None => Ok(()),
+        }
+    }
+}
+
+const DISK_TEMP_PREFIX: &str = ".pmtiles-cache-";
+
+// Entries waiting to be written are dropped beyond this many bytes, which only happens
+// when the disk is slower than the sources being cached
+const DISK_MAX_QUEUED_BYTES: u64 = 64 * 1024 * 1024;
+
+// Name of the file storing the entry of a key. The key itself is stored at the start of
+// the file, so that entries can be indexed again after a restart.
+fn disk_file_name(key: &str) -> String {
+    format!("{:016x}", xxh3_64(key.as_bytes()))
+}
+
+fn read_disk_key(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path)?;
+    let key_length = file.read_u32::<LittleEndian>()? as usize;
+    let mut key = vec![0; key_length];
+    file.read_exact(&mut key)?;
+    Ok(String::from_utf8(key)?)
+}
+
+// Run blocking file I/O without holding up the other tasks of a multi-threaded runtime
+fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+struct DiskEntry {
+    key: String,
+    size: u64,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct DiskState {
+    // Entries by the name of their file
+    entries: HashMap<String, DiskEntry>,
+    // File names by the tick of their latest use, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+    // Size of the entries waiting to be written
+    queued: u64,
+    // Incremented on every invalidation, so that writes queued before it are dropped
+    generation: u64,
+}
+
+impl DiskState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, name: String, key: String, size: u64) {
+        self.remove(&name);
+        let tick = self.next_tick();
+        self.order.insert(tick, name.clone());
+        self.entries.insert(name, DiskEntry { key, size, tick });
+        self.size += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.order.remove(&entry.tick);
+            self.size -= entry.size;
+        }
+    }
+
+    // Drop the least recently used entries until at most max_bytes remain, returning the
+    // names of the files to remove
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+enum DiskOp {
+    Write {
+        name: String,
+        key: String,
+        data: Bytes,
+        generation: u64,
+    },
+    Remove(Vec<String>),
+    Flush(mpsc::Sender<()>),
+}
+
+// Files of a DiskCache, shared with the thread writing them
+struct DiskStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<DiskState>,
+}
+
+impl DiskStore {
+    fn remove_file(&self, name: &str) {
+        if let Err(err) = std::fs::remove_file(self.dir.join(name)) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("failed to remove cache file {}: {}", name, err);
+            }
+        }
+    }
+
+    // Write the file of an entry under a temporary name and rename it into place, so that
+    // readers and later runs never see partially written entries
+    fn write_file(&self, name: &str, key: &str, data: &[u8]) -> std::io::Result<u64> {
+        let mut file = tempfile::Builder::new()
+            .prefix(DISK_TEMP_PREFIX)
+            .tempfile_in(&self.dir)?;
+        file.write_u32::<LittleEndian>(key.len() as u32)?;
+        file.write_all(key.as_bytes())?;
+        file.write_all(data)?;
+        file.persist(self.dir.join(name)).map_err(|err| err.error)?;
+        Ok((4 + key.len() + data.len()) as u64)
+    }
+
+    fn write(&self, name: String, key: String, data: Bytes, generation: u64) {
+        {
+            let Ok(mut state) = self.state.lock() else {
+                return;
+            };
+            state.queued -= data.len() as u64;
+            if state.generation != generation {
+                return;
+            }
+        }
+        let size = match self.write_file(&name, &key, &data) {
+            Ok(size) => size,
+            Err(err) => {
+                tracing::warn!("failed to write cache file of key {}: {}", key, err);
+                return;
+            }
+        };
+        let evicted = {
+            let Ok(mut state) = self.state.lock() else {
+                return;
+            };
+            // Invalidated while the file was being written
+            if state.generation != generation {
+                state.remove(&name);
+                vec![name]
+            } else {
+                state.insert(name, key, size);
+                state.evict(self.max_bytes)
+            }
+        };
+        for name in evicted {
+            self.remove_file(&name);
+        }
+    }
+
+    fn run(&self, ops: mpsc::Receiver<DiskOp>) {
+        for op in ops {
+            match op {
+                DiskOp::Write {
+                    name,
+                    key,
+                    data,
+                    generation,
+                } => self.write(name, key, data, generation),
+                DiskOp::Remove(names) => {
+                    for name in names {
+                        self.remove_file(&name);
+                    }
+                }
+                DiskOp::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+}
+
+// Cache storing each entry as a file in a directory, so that cached data survives restarts.
+// Holds at most max_bytes of files, evicting the least recently used entries first. Entries
+// stored by earlier runs are taken into use oldest first by their modification time.
+// Files are written and removed by a thread of their own, as the Cache trait is not async,
+// and an entry can be read once its file has been written. Files are read in place, moving
+// the other tasks of a multi-threaded runtime off the reading worker.
+pub struct DiskCache {
+    store: Arc<DiskStore>,
+    ops: Option<mpsc::Sender<DiskOp>>,
+    writer: Option<JoinHandle<()>>,
+    tiles: bool,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut stored = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            let metadata = file.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            // Files left partially written by an earlier run
+            if name.starts_with(DISK_TEMP_PREFIX) {
+                std::fs::remove_file(file.path())?;
+                continue;
+            }
+            if name.len() != 16 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+            match read_disk_key(&file.path()) {
+                Ok(key) if disk_file_name(&key) == name => {
+                    stored.push((metadata.modified()?, name, key, metadata.len()))
+                }
+                _ => {
+                    tracing::warn!("removing invalid cache file {}", file.path().display());
+                    std::fs::remove_file(file.path())?;
+                }
+            }
+        }
+        stored.sort_unstable();
+
+        let mut state = DiskState::default();
+        for (_, name, key, size) in stored {
+            state.insert(name, key, size);
+        }
+        let evicted = state.evict(max_bytes);
+        tracing::debug!(
+            "found {} cached entries in {}",
+            state.entries.len(),
+            dir.display()
+        );
+        let store = Arc::new(DiskStore {
+            dir,
+            max_bytes,
+            state: Mutex::new(state),
+        });
+        for name in evicted {
+            store.remove_file(&name);
+        }
+
+        let (ops, receiver) = mpsc::channel();
+        let writer = {
+            let store = store.clone();
+            std::thread::Builder::new()
+                .name("disk-cache-writer".into())
+                .spawn(move || store.run(receiver))?
+        };
+        Ok(DiskCache {
+            store,
+            ops: Some(ops),
+            writer: Some(writer),
+            tiles: false,
+        })
+    }
+
+    // Store tile contents as well, which are otherwise only kept by in-memory caches
+    pub fn with_tiles(mut self) -> Self {
+        self.tiles = true;
+        self
+    }
+
+    // Total size of the cache files in bytes
+    pub fn size(&self) -> u64 {
+        self.store.state.lock().map(|state| state.size).unwrap_or(0)
+    }
+
+    // Wait until the entries set so far have been written. This blocks the calling thread.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.send(DiskOp::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+
+    fn send(&self, op: DiskOp) -> Result<(), CacheError> {
+        self.ops
+            .as_ref()
+            .and_then(|ops| ops.send(op).ok())
+            .ok_or_else(|| CacheError::SetError("disk cache writer has stopped".into()))
+    }
+}
+
+// Entries still waiting to be written are written before the cache is dropped
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        self.ops.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Cache for DiskCache {
+    fn get(&self, key: &str) -> Option<Bytes> {
+        let name = disk_file_name(key);
+        {
+            let mut state = self.store.state.lock().ok()?;
+            let entry = state.entries.get(&name)?;
+            if entry.key != key {
+                return None;
+            }
+            let old_tick = entry.tick;
+            let tick = state.next_tick();
+            state.order.remove(&old_tick);
+            state.order.insert(tick, name.clone());
+            state.entries.get_mut(&name)?.tick = tick;
+        }
+        let data = match run_blocking(|| std::fs::read(self.store.dir.join(&name))) {
+            Ok(data) => Bytes::from(data),
+            Err(err) => {
+                tracing::debug!("failed to read cache file of key {}: {}", key, err);
+                return None;
+            }
+        };
+        let offset = 4 + key.len();
+        if data.get(4..offset) != Some(key.as_bytes()) {
+            return None;
+        }
+        Some(data.slice(offset..))
+    }
+
+    fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError> {
+        if (4 + key.len() + data.len()) as u64 > self.store.max_bytes {
+            tracing::debug!("not caching key {} larger than the cache", key);
+            return Ok(());
+        }
+        let mut state = self
+            .store
+            .state
+            .lock()
+            .map_err(|err| CacheError::SetError(err.to_string()))?;
+        if state.queued + data.len() as u64 > DISK_MAX_QUEUED_BYTES {
+            tracing::debug!("not caching key {} as the disk cache is behind", key);
+            return Ok(());
+        }
+        state.queued += data.len() as u64;
+        self.send(DiskOp::Write {
+            name: disk_file_name(key),
+            key: key.into(),
+            data,
+            generation: state.generation,
+        })
+    }
+
+    fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+        let mut state = self
+            .store
+            .state
+            .lock()
+            .map_err(|err| CacheError::SetError(err.to_string()))?;
+        state.generation += 1;
+        let names: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| is_derived_key(&entry.key, key))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &names {
+            state.remove(name);
+        }
+        self.send(DiskOp::Remove(names))
+    }
+
+    fn get_tile(&self, key: &str, _z: u8) -> Option<Bytes> {
+        if !self.tiles {
+            return None;
+        }
+        self.get(key)
+    }
+
+    fn set_tile(&self, key: &str, _z: u8, data: Bytes) -> Result<(), CacheError> {
+        if !self.tiles {
+            return Ok(());
+        }
+        self.set(key, data)
+    }
+}
+
+// Cache in two tiers, e.g. an in-memory cache in front of a DiskCache. Entries are stored
+// in both tiers, and entries found only in the second tier are copied to the first one.
+// Failing to store an entry in the first tier is only logged, as the second tier still
+// holds it.
+pub struct TieredCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+}
+
+fn log_l1_error(key: &str, res: Result<(), CacheError>) {
+    if let Err(err) = res {
+        tracing::warn!("failed to cache key {} in the first tier: {}", key, err);
+    }
+}
+
+impl<L1: Cache, L2: Cache> TieredCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        TieredCache { l1, l2 }
+    }
+}
+
+impl<L1: Cache, L2: Cache> Cache for TieredCache<L1, L2> {
+    fn get(&self, key: &str) -> Option<Bytes> {
+        if let Some(data) = self.l1.get(key) {
+            return Some(data);
+        }
+        let data = self.l2.get(key)?;
+        log_cache_set(key, self.l1.set(key, data.clone()));
+        Some(data)
+    }
+
+    fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError> {
+        log_l1_error(key, self.l1.set(key, data.clone()));
+        self.l2.set(key, data)
+    }
+
+    fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+        // Invalidate both tiers even if the first one fails, so that neither serves stale data
+        let l1 = self.l1.invalidate(key);
+        let l2 = self.l2.invalidate(key);
+        l1.and(l2)
+    }
+
+    fn get_directory(&self, key: &str) -> Option<Arc<Directory>> {
+        if let Some(directory) = self.l1.get_directory(key) {
+            return Some(directory);
+        }
+        let directory = self.l2.get_directory(key)?;
+        log_cache_set(key, self.l1.set_directory(key, directory.clone()));
+        Some(directory)
+    }
+
+    fn set_directory(&self, key: &str, directory: Arc<Directory>) -> Result<(), CacheError> {
+        log_l1_error(key, self.l1.set_directory(key, directory.clone()));
+        self.l2.set_directory(key, directory)
+    }
+
+    fn get_tile(&self, key: &str, z: u8) -> Option<Bytes> {
+        if let Some(data) = self.l1.get_tile(key, z) {
+            return Some(data);
+        }
+        let data = self.l2.get_tile(key, z)?;
+        log_cache_set(key, self.l1.set_tile(key, z, data.clone()));
+        Some(data)
+    }
+
+    fn set_tile(&self, key: &str, z: u8, data: Bytes) -> Result<(), CacheError> {
+        log_l1_error(key, self.l1.set_tile(key, z, data.clone()));
+        self.l2.set_tile(key, z, data)
+    }
+}
+
+#[test]
+fn test_lru_cache() {
+    let cache = LruCache::new(30, None);
+    cache.set("a", Bytes::from(vec![0; 9])).unwrap();
+    cache.set("b", Bytes::from(vec![1; 9])).unwrap();
+    cache.set("c", Bytes::from(vec![2; 9])).unwrap();
+    assert_eq!(cache.size(), 30);
+
+    // Using a makes b the least recently used entry
+    assert_eq!(cache.get("a"), Some(Bytes::from(vec![0; 9])));
+    cache.set("d", Bytes::from(vec![3; 4])).unwrap();
+    assert_eq!(cache.get("b"), None);
+    assert!(cache.get("a").is_some());
+    assert!(cache.get("c").is_some());
+    assert!(cache.get("d").is_some());
+    assert_eq!(cache.size(), 25);
+
+    // Replacing an entry updates the size
+    cache.set("d", Bytes::from(vec![3; 9])).unwrap();
+    assert_eq!(cache.size(), 30);
+
+    // Entries larger than the whole cache are not stored
+    cache.set("e", Bytes::from(vec![4; 40])).unwrap();
+    assert_eq!(cache.get("e"), None);
+    assert_eq!(cache.size(), 30);
+}
+
+#[test]
+fn test_lru_cache_ttl() {
+    let cache = LruCache::new(1024, Some(Duration::from_millis(20)));
+    cache.set("a", Bytes::from_static(b"data")).unwrap();
+    assert_eq!(cache.get("a"), Some(Bytes::from_static(b"data")));
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(cache.get("a"), None);
+    assert_eq!(cache.size(), 0);
+}
+
+#[test]
+fn test_invalidate() {
+    let caches: [Box<dyn Cache>; 2] = [
+        Box::new(InMemoryCache::new()),
+        Box::new(LruCache::new(1024, None)),
+    ];
+    for cache in caches {
+        cache
+            .set("a.pmtiles", Bytes::from_static(b"header"))
+            .unwrap();
+        cache
+            .set("a.pmtiles|metadata", Bytes::from_static(b"{}"))
+            .unwrap();
+        cache
+            .set_directory("a.pmtiles|root", Arc::new(Directory::default()))
+            .unwrap();
+        cache
+            .set("a.pmtiles2", Bytes::from_static(b"other"))
+            .unwrap();
+        cache.invalidate("a.pmtiles").unwrap();
+        assert_eq!(cache.get("a.pmtiles"), None);
+        assert_eq!(cache.get("a.pmtiles|metadata"), None);
+        assert!(cache.get_directory("a.pmtiles|root").is_none());
+        assert!(cache.get("a.pmtiles2").is_some());
+    }
+}
+
+#[test]
+fn test_tile_cache() {
+    let tiles = TileCache::new(20, Some(6));
+    tiles
+        .set("a|tile|0|e", 0, Bytes::from(vec![0; 30]))
+        .unwrap();
+    tiles.set("a|tile|1|e", 7, Bytes::from(vec![1; 8])).unwrap();
+    tiles.set("a|tile|2|e", 7, Bytes::from(vec![2; 8])).unwrap();
+
+    // Pinned tiles stay regardless of the size budget, other tiles are evicted
+    assert_eq!(tiles.get("a|tile|0|e"), Some(Bytes::from(vec![0; 30])));
+    assert_eq!(tiles.get("a|tile|1|e"), None);
+    assert!(tiles.get("a|tile|2|e").is_some());
+
+    // Pinning a tile of a replaced archive drops the pinned tiles of the previous one
+    tiles.set("b|tile|0|e", 0, Bytes::from(vec![3; 2])).unwrap();
+    tiles.set("a|tile|1|f", 1, Bytes::from(vec![4; 2])).unwrap();
+    assert_eq!(tiles.get("a|tile|0|e"), None);
+    assert!(tiles.get("a|tile|1|f").is_some());
+    assert!(tiles.get("b|tile|0|e").is_some());
+    tiles
+        .set("a|tile|0|f", 0, Bytes::from(vec![0; 30]))
+        .unwrap();
+
+    let cache = InMemoryCache::new().with_tiles(tiles);
+    assert!(cache.get_tile("a|tile|0|f", 0).is_some());
+    cache.invalidate("a").unwrap();
+    assert_eq!(cache.get_tile("a|tile|0|f", 0), None);
+    assert_eq!(cache.get_tile("a|tile|2|e", 7), None);
+}
+
+#[test]
+fn test_disk_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = DiskCache::new(dir.path(), 100).unwrap();
+    for (key, data) in [
+        ("a.pmtiles", vec![0; 20]),
+        ("a.pmtiles|root", vec![1; 10]),
+        ("b.pmtiles", vec![2; 10]),
+    ] {
+        cache.set(key, Bytes::from(data)).unwrap();
+        cache.flush();
+        // Entries of earlier runs are ordered by the modification times of their files
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(cache.get("a.pmtiles"), Some(Bytes::from(vec![0; 20])));
+    // Files hold the keys along with their lengths
+    assert_eq!(cache.size(), 33 + 28 + 23);
+
+    // Entries survive a restart, and the least recently used ones are evicted first
+    drop(cache);
+    let cache = DiskCache::new(dir.path(), 100).unwrap();
+    assert_eq!(cache.size(), 33 + 28 + 23);
+    assert_eq!(cache.get("a.pmtiles|root"), Some(Bytes::from(vec![1; 10])));
+    cache.set("c.pmtiles", Bytes::from(vec![3; 20])).unwrap();
+    cache.flush();
+    assert_eq!(cache.get("a.pmtiles"), None);
+    assert!(cache.get("a.pmtiles|root").is_some());
+    assert!(cache.get("b.pmtiles").is_some());
+    assert!(cache.get("c.pmtiles").is_some());
+
+    // Entries queued before an invalidation are not written
+    cache
+        .set("a.pmtiles|metadata", Bytes::from(vec![4; 2]))
+        .unwrap();
+    cache.invalidate("a.pmtiles").unwrap();
+    assert_eq!(cache.get("a.pmtiles|root"), None);
+    cache.flush();
+    assert_eq!(cache.get("a.pmtiles|metadata"), None);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn test_tiered_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = TieredCache::new(
+        InMemoryCache::new(),
+        DiskCache::new(dir.path(), 1024).unwrap().with_tiles(),
+    );
+    cache
+        .set("a.pmtiles", Bytes::from_static(b"header"))
+        .unwrap();
+    cache
+        .set_directory("a.pmtiles|root", Arc::new(Directory::default()))
+        .unwrap();
+    cache
+        .set_tile("a.pmtiles|tile|0|e", 0, Bytes::from_static(b"tile"))
+        .unwrap();
+    drop(cache);
+
+    // A new in-memory cache is filled from the disk cache
+    let cache = TieredCache::new(
+        InMemoryCache::new().with_tiles(TileCache::new(1024, None)),
+        DiskCache::new(dir.path(), 1024).unwrap().with_tiles(),
+    );
+    assert_eq!(cache.get("a.pmtiles"), Some(Bytes::from_static(b"header")));
+    assert!(cache.l1.get("a.pmtiles").is_some());
+    assert!(cache.get_directory("a.pmtiles|root").is_some());
+    assert!(cache.l1.get_directory("a.pmtiles|root").is_some());
+    assert_eq!(
+        cache.get_tile("a.pmtiles|tile|0|e", 0),
+        Some(Bytes::from_static(b"tile"))
+    );
+    assert!(cache.l1.get_tile("a.pmtiles|tile|0|e", 0).is_some());
+
+    cache.invalidate("a.pmtiles").unwrap();
+    assert_eq!(cache.l2.get("a.pmtiles"), None);
+    assert_eq!(cache.get_tile("a.pmtiles|tile|0|e", 0), None);
+}
+
+#[test]
+fn test_tiered_cache_writes() {
+    struct FailingCache;
+    impl Cache for FailingCache {
+        fn get(&self, _key: &str) -> Option<Bytes> {
+            None
+        }
+        fn set(&self, key: &str, _data: Bytes) -> Result<(), CacheError> {
+            Err(CacheError::SetError(key.into()))
+        }
+        fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+            Err(CacheError::SetError(key.into()))
+        }
+    }
+
+    // Entries are stored in the second tier even when the first one fails
+    let dir = tempfile::tempdir().unwrap();
+    let cache = TieredCache::new(FailingCache, DiskCache::new(dir.path(), 1024).unwrap());
+    cache
+        .set("a.pmtiles", Bytes::from_static(b"header"))
+        .unwrap();
+    // Tiles are only stored on disk when enabled
+    cache
+        .set_tile("a.pmtiles|tile|0|e", 0, Bytes::from_static(b"tile"))
+        .unwrap();
+    cache.l2.flush();
+    assert_eq!(cache.get("a.pmtiles"), Some(Bytes::from_static(b"header")));
+    assert_eq!(cache.get_tile("a.pmtiles|tile|0|e", 0), None);
+    assert_eq!(cache.l2.size(), 4 + 9 + 6);
+
+    // The second tier is invalidated as well before the error of the first one is returned
+    assert!(cache.invalidate("a.pmtiles").is_err());
+    assert_eq!(cache.get("a.pmtiles"), None);
+}
@@ -1,8 +1,10 @@
 use brotli_decompressor::Decompressor;
-use std::io::Read;
-use zstd;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
 use zune_inflate::DeflateDecoder;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Compression {
     Unknown,
     None,
@@ -23,27 +25,78 @@ impl From<u8> for Compression {
     }
 }
 
-pub fn decompress(
-    data: &[u8],
-    compression: Compression,
-) -> anyhow::Result<impl IntoIterator<Item = u8>> {
+impl From<Compression> for u8 {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::Unknown => 0,
+            Compression::None => 1,
+            Compression::Gzip => 2,
+            Compression::Brotli => 3,
+            Compression::Zstd => 4,
+        }
+    }
+}
+
+// Detect the compression of a tile from its magic bytes
+pub fn detect_compression(data: &[u8]) -> Compression {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+pub fn compress(data: &[u8], compression: Compression) -> anyhow::Result<Vec<u8>> {
     match compression {
-        Compression::Unknown => Ok(data.to_vec()),
         Compression::None => Ok(data.to_vec()),
         Compression::Gzip => {
-            let mut decoder = DeflateDecoder::new(data);
+            let mut encoder = GzEncoder::new(
+                Vec::with_capacity(data.len() / 2),
+                flate2::Compression::default(),
+            );
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        Compression::Brotli | Compression::Unknown => {
+            anyhow::bail!("unsupported compression for writing: {:?}", compression)
+        }
+    }
+}
+
+impl Compression {
+    // Name of the compression as an HTTP content coding
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::Gzip => Some("gzip"),
+            Compression::Brotli => Some("br"),
+            Compression::Zstd => Some("zstd"),
+            Compression::Unknown | Compression::None => None,
+        }
+    }
+}
+
+// Decompress data, which is returned as it is, without copying it, if it is uncompressed
+pub fn decompress(data: Bytes, compression: Compression) -> anyhow::Result<Bytes> {
+    match compression {
+        Compression::Unknown => Ok(data),
+        Compression::None => Ok(data),
+        Compression::Gzip => {
+            let mut decoder = DeflateDecoder::new(&data);
             let decompressed = decoder.decode_gzip()?;
-            Ok(decompressed)
+            Ok(decompressed.into())
         }
         Compression::Brotli => {
-            let mut decoder = Decompressor::new(data, 4096);
+            let mut decoder = Decompressor::new(&data[..], 4096);
             let mut decompressed = Vec::with_capacity(data.len());
             decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
+            Ok(decompressed.into())
         }
         Compression::Zstd => {
-            let decompressed = zstd::decode_all(data)?;
-            Ok(decompressed)
+            let decompressed = zstd::decode_all(&data[..])?;
+            Ok(decompressed.into())
         }
     }
 }
@@ -0,0 +1,531 @@
+use crate::cache::InMemoryCache;
+use crate::compress::Compression;
+use crate::fetcher::Fetcher;
+use crate::helpers::{
+    get_archive_range, get_headers, get_leaf_directory, tile_id_to_zxy, zxy_to_tile_id,
+};
+use crate::models::{Directory, Headers, TileType};
+use crate::pmtiles::{fetch_metadata, parse_metadata, PMTilesError};
+use crate::utils::{lonlat_to_tile, tile_bounds, TILES_PER_LEVEL};
+use crate::writer::PMTilesWriter;
+use serde_json::Value;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::Arc;
+
+// Tile data closer together than this is read with a single request
+const MAX_GAP_BYTES: u64 = 256 * 1024;
+// Size limit of a single batched request
+const MAX_BATCH_BYTES: u64 = 32 * 1024 * 1024;
+
+type Ring = Vec<[f64; 2]>;
+
+// Area to extract in longitude and latitude, either a bounding box as
+// [min_lon, min_lat, max_lon, max_lat] or polygons given as their rings, the first
+// of which is the exterior and the rest holes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    BBox([f64; 4]),
+    Polygons(Vec<Vec<Ring>>),
+}
+
+fn parse_rings(value: &Value) -> anyhow::Result<Vec<Ring>> {
+    let rings: Vec<Vec<Vec<f64>>> = serde_json::from_value(value.clone())?;
+    rings
+        .into_iter()
+        .map(|ring| {
+            ring.into_iter()
+                .map(|point| match point[..] {
+                    [lon, lat, ..] => Ok([lon, lat]),
+                    _ => Err(anyhow::anyhow!("invalid GeoJSON position")),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn collect_polygons(value: &Value, polygons: &mut Vec<Vec<Ring>>) -> anyhow::Result<()> {
+    let members = |key: &str| {
+        value
+            .get(key)
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow::anyhow!("GeoJSON object is missing {}", key))
+    };
+    match value.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => {
+            for feature in members("features")? {
+                collect_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_polygons(&value["geometry"], polygons)?,
+        Some("GeometryCollection") => {
+            for geometry in members("geometries")? {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        Some("Polygon") => polygons.push(parse_rings(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in members("coordinates")? {
+                polygons.push(parse_rings(polygon)?);
+            }
+        }
+        other => anyhow::bail!("unsupported GeoJSON type {:?}", other),
+    }
+    Ok(())
+}
+
+// Whether a line segment crosses or lies within a rectangle, by Liang-Barsky clipping
+fn segment_intersects_rect(a: [f64; 2], b: [f64; 2], rect: [f64; 4]) -> bool {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let (mut t0, mut t1) = (0_f64, 1_f64);
+    let sides = [
+        (-dx, a[0] - rect[0]),
+        (dx, rect[2] - a[0]),
+        (-dy, a[1] - rect[1]),
+        (dy, rect[3] - a[1]),
+    ];
+    for (p, q) in sides {
+        if p == 0. {
+            if q < 0. {
+                return false;
+            }
+            continue;
+        }
+        let t = q / p;
+        if p < 0. {
+            t0 = t0.max(t);
+        } else {
+            t1 = t1.min(t);
+        }
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+fn edges(rings: &[Ring]) -> impl Iterator<Item = ([f64; 2], [f64; 2])> + '_ {
+    rings.iter().flat_map(|ring| {
+        ring.iter()
+            .copied()
+            .zip(ring.iter().copied().cycle().skip(1))
+    })
+}
+
+// Even-odd test, so points within holes are outside
+fn polygon_contains(rings: &[Ring], [x, y]: [f64; 2]) -> bool {
+    edges(rings)
+        .filter(|(a, b)| {
+            (a[1] > y) != (b[1] > y) && x < (b[0] - a[0]) * (y - a[1]) / (b[1] - a[1]) + a[0]
+        })
+        .count()
+        % 2
+        == 1
+}
+
+impl Region {
+    // Read the polygons of a GeoJSON geometry, feature or feature collection
+    pub fn from_geojson(value: &Value) -> anyhow::Result<Self> {
+        let mut polygons = Vec::new();
+        collect_polygons(value, &mut polygons)?;
+        if polygons.iter().flatten().flatten().next().is_none() {
+            anyhow::bail!("GeoJSON contains no polygons");
+        }
+        Ok(Region::Polygons(polygons))
+    }
+
+    pub fn bounds(&self) -> [f64; 4] {
+        match self {
+            Region::BBox(bbox) => *bbox,
+            Region::Polygons(polygons) => polygons.iter().flatten().flatten().fold(
+                [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+                |[min_lon, min_lat, max_lon, max_lat], [lon, lat]| {
+                    [
+                        min_lon.min(*lon),
+                        min_lat.min(*lat),
+                        max_lon.max(*lon),
+                        max_lat.max(*lat),
+                    ]
+                },
+            ),
+        }
+    }
+
+    // Whether a tile is disjoint from the region (None), within it (Some(true)) or only
+    // partly covered by it (Some(false)). Tiles within the bounds of a bounding box are
+    // always within it.
+    fn covers_tile(&self, z: u8, x: u64, y: u64) -> Option<bool> {
+        let Region::Polygons(polygons) = self else {
+            return Some(true);
+        };
+        let rect = tile_bounds(z, x, y);
+        let center = [(rect[0] + rect[2]) / 2., (rect[1] + rect[3]) / 2.];
+        let crossed =
+            |rings: &Vec<Ring>| edges(rings).any(|(a, b)| segment_intersects_rect(a, b, rect));
+        if polygons.iter().any(crossed) {
+            Some(false)
+        } else if polygons.iter().any(|rings| polygon_contains(rings, center)) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    // Sorted and disjoint ranges of the ids of the tiles intersecting the region within a
+    // zoom range. The tiles below a tile have consecutive ids on each zoom level, so the
+    // region is covered by walking down from the tiles it contains whole without listing
+    // every tile id.
+    fn tile_id_ranges(&self, min_zoom: u8, max_zoom: u8) -> anyhow::Result<Vec<Range<u64>>> {
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounds();
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for z in min_zoom..=max_zoom {
+            let (min_x, min_y) = lonlat_to_tile(z, min_lon, max_lat);
+            let (max_x, max_y) = lonlat_to_tile(z, max_lon, min_lat);
+            let mut tiles = vec![(0_u8, 0_u64, 0_u64)];
+            while let Some((tile_z, x, y)) = tiles.pop() {
+                // Tiles at zoom level z below the tile
+                let shift = z - tile_z;
+                let (first_x, last_x) = (x << shift, ((x + 1) << shift) - 1);
+                let (first_y, last_y) = (y << shift, ((y + 1) << shift) - 1);
+                if last_x < min_x || first_x > max_x || last_y < min_y || first_y > max_y {
+                    continue;
+                }
+                let within_bounds =
+                    first_x >= min_x && last_x <= max_x && first_y >= min_y && last_y <= max_y;
+                let Some(within) = self.covers_tile(tile_z, x, y) else {
+                    continue;
+                };
+                if (within && within_bounds) || shift == 0 {
+                    let index =
+                        zxy_to_tile_id(tile_z as u64, x, y)? - TILES_PER_LEVEL[tile_z as usize];
+                    let start = TILES_PER_LEVEL[z as usize] + (index << (2 * shift));
+                    ranges.push(start..start + (1 << (2 * shift)));
+                } else {
+                    for (dx, dy) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                        tiles.push((tile_z + 1, 2 * x + dx, 2 * y + dy));
+                    }
+                }
+            }
+        }
+        ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        Ok(merged)
+    }
+}
+
+// Group tiles sorted by offset into ranges of tiles that are read with one request
+fn batch_tiles(tiles: &[(u64, u64, u64)]) -> Vec<Range<usize>> {
+    let mut batches: Vec<Range<usize>> = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_end = 0;
+    for (i, (offset, length, _)) in tiles.iter().enumerate() {
+        let batch = batches.last_mut().filter(|_| {
+            *offset <= batch_end + MAX_GAP_BYTES && offset + length - batch_start <= MAX_BATCH_BYTES
+        });
+        if let Some(batch) = batch {
+            batch.end = i + 1;
+            batch_end = batch_end.max(offset + length);
+        } else {
+            batches.push(i..i + 1);
+            batch_start = *offset;
+            batch_end = offset + length;
+        }
+    }
+    batches
+}
+
+// Collect the (offset, length, tile id) of the tiles within the wanted ranges of tile ids,
+// only reading the leaf directories that may address them
+async fn find_tiles<T: Fetcher>(
+    path: &str,
+    headers: &Headers,
+    root: Arc<Directory>,
+    wanted: &[Range<u64>],
+    client: &T,
+) -> Result<Vec<(u64, u64, u64)>, PMTilesError> {
+    let mut tiles = Vec::new();
+    let mut directories = vec![(root, u64::MAX)];
+    while let Some((directory, directory_end)) = directories.pop() {
+        for (i, entry) in directory.iter().enumerate() {
+            let entry_end = if entry.run_length > 0 {
+                entry.tile_id.saturating_add(entry.run_length)
+            } else {
+                directory
+                    .get(i + 1)
+                    .map_or(directory_end, |next| next.tile_id)
+            };
+            let first = wanted.partition_point(|range| range.end <= entry.tile_id);
+            let mut matching = wanted[first..]
+                .iter()
+                .take_while(|range| range.start < entry_end);
+            if entry.run_length > 0 {
+                for range in matching {
+                    let tile_ids = range.start.max(entry.tile_id)..range.end.min(entry_end);
+                    tiles.extend(tile_ids.map(|tile_id| (entry.offset, entry.length, tile_id)));
+                }
+            } else if matching.next().is_some() {
+                let leaf = get_leaf_directory(
+                    path,
+                    headers,
+                    headers.leaf_directory_offset + entry.offset,
+                    entry.length,
+                    client,
+                    None as Option<&InMemoryCache>,
+                )
+                .await?;
+                directories.push((leaf, entry_end));
+            }
+        }
+    }
+    tiles.sort_unstable();
+    Ok(tiles)
+}
+
+// Copy tiles given as (offset, length, tile id) sorted by offset into a writer, reading
+// nearby tile data with batched requests
+pub(crate) async fn write_tiles<T: Fetcher>(
+    path: &str,
+    headers: &Headers,
+    tiles: &[(u64, u64, u64)],
+    client: &T,
+    writer: &mut PMTilesWriter,
+) -> Result<(), PMTilesError> {
+    let batches = batch_tiles(tiles);
+    tracing::info!(
+        "copying {} tiles from {} with {} requests",
+        tiles.len(),
+        path,
+        batches.len()
+    );
+    for batch in batches {
+        let batch = &tiles[batch];
+        let start = batch[0].0;
+        let end = batch
+            .iter()
+            .map(|(offset, length, _)| offset + length)
+            .max()
+            .unwrap_or(start);
+        let data = get_archive_range(
+            path,
+            headers,
+            headers.tile_data_offset + start,
+            end - start,
+            client,
+        )
+        .await?;
+        for (offset, length, tile_id) in batch {
+            let tile_data = data
+                .get((offset - start) as usize..(offset - start + length) as usize)
+                .ok_or_else(|| anyhow::anyhow!("tile data of tile id {} is truncated", tile_id))?;
+            let (z, x, y) = tile_id_to_zxy(*tile_id)?;
+            writer.add_tile(z as u8, x, y, tile_data)?;
+        }
+    }
+    Ok(())
+}
+
+// Write the tiles of an archive that intersect a region within a zoom range into a new
+// archive. The zoom range defaults to the zoom levels of the source archive. Only the
+// needed leaf directories are read and nearby tiles are fetched in batched range requests.
+pub async fn extract<T: Fetcher, W: Write>(
+    path: &str,
+    client: &T,
+    region: &Region,
+    min_zoom: Option<u8>,
+    max_zoom: Option<u8>,
+    out: &mut W,
+) -> Result<Headers, PMTilesError> {
+    let [min_lon, min_lat, max_lon, max_lat] = region.bounds();
+    if !(min_lon <= max_lon && min_lat <= max_lat) {
+        return Err(PMTilesError::BadRequest("invalid region bounds".into()));
+    }
+    let (headers, root) = get_headers(path, client, None as Option<&InMemoryCache>).await?;
+    if headers.spec_version == 2 {
+        return Err(PMTilesError::BadRequest(
+            "version 2 archives must be upgraded before extracting from them".into(),
+        ));
+    }
+    let metadata = fetch_metadata(path, &headers, client, None as Option<&InMemoryCache>).await?;
+    let metadata = parse_metadata(&metadata)?;
+    let min_zoom = min_zoom.unwrap_or(headers.min_zoom).max(headers.min_zoom);
+    let max_zoom = max_zoom.unwrap_or(headers.max_zoom).min(headers.max_zoom);
+    let wanted = region.tile_id_ranges(min_zoom, max_zoom)?;
+    let tiles = find_tiles(path, &headers, root, &wanted, client).await?;
+    if tiles.is_empty() {
+        return Err(PMTilesError::NotFound(Some(
+            "no tiles found within the region".into(),
+        )));
+    }
+
+    let mut writer = PMTilesWriter::new(
+        TileType::from(headers.tile_type),
+        Compression::from(headers.tile_compression),
+    )?;
+    writer.set_metadata(metadata);
+    writer.set_bounds(
+        min_lon.max(headers.min_lon),
+        min_lat.max(headers.min_lat),
+        max_lon.min(headers.max_lon),
+        max_lat.min(headers.max_lat),
+    );
+    write_tiles(path, &headers, &tiles, client, &mut writer).await?;
+    Ok(writer.finish(out)?)
+}
+
+#[test]
+fn test_batch_tiles() {
+    let tiles = [
+        (0, 100, 1),
+        (0, 100, 2),
+        (100, 50, 3),
+        (MAX_GAP_BYTES + 150, 10, 4),
+        (2 * MAX_GAP_BYTES + 200, 10, 5),
+        (2 * MAX_GAP_BYTES + 210, MAX_BATCH_BYTES, 6),
+    ];
+    assert_eq!(batch_tiles(&tiles), vec![0..4, 4..5, 5..6]);
+}
+
+#[test]
+fn test_region_from_geojson() {
+    let geojson = serde_json::json!({
+        "type": "FeatureCollection",
+        "features": [{
+            "type": "Feature",
+            "properties": {},
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [
+                    [[0., 0.], [10., 0.], [10., 10.], [0., 10.], [0., 0.]],
+                    [[4., 4.], [6., 4.], [6., 6.], [4., 6.], [4., 4.]]
+                ]
+            }
+        }]
+    });
+    let region = Region::from_geojson(&geojson).unwrap();
+    assert_eq!(region.bounds(), [0., 0., 10., 10.]);
+    let Region::Polygons(polygons) = &region else {
+        panic!("expected polygons");
+    };
+    assert!(polygon_contains(&polygons[0], [2., 2.]));
+    assert!(!polygon_contains(&polygons[0], [5., 5.]));
+    assert!(!polygon_contains(&polygons[0], [11., 5.]));
+    assert!(
+        Region::from_geojson(&serde_json::json!({"type": "Point", "coordinates": [0., 0.]}))
+            .is_err()
+    );
+}
+
+#[test]
+fn test_region_tile_id_ranges() {
+    let region = Region::Polygons(vec![vec![
+        vec![[-10., -20.], [40., -5.], [20., 50.], [-10., -20.]],
+        vec![[5., 0.], [15., 0.], [15., 10.], [5., 0.]],
+    ]]);
+    let ranges = region.tile_id_ranges(2, 9).unwrap();
+    assert!(ranges.windows(2).all(|pair| pair[0].end < pair[1].start));
+    // Same tiles as testing each tile within the bounds of the region
+    let [min_lon, min_lat, max_lon, max_lat] = region.bounds();
+    let mut expected = Vec::new();
+    for z in 2..=9 {
+        let (min_x, min_y) = lonlat_to_tile(z, min_lon, max_lat);
+        let (max_x, max_y) = lonlat_to_tile(z, max_lon, min_lat);
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                if region.covers_tile(z, x, y).is_some() {
+                    expected.push(zxy_to_tile_id(z as u64, x, y).unwrap());
+                }
+            }
+        }
+    }
+    expected.sort_unstable();
+    let tile_ids: Vec<u64> = ranges.into_iter().flatten().collect();
+    assert_eq!(tile_ids, expected);
+    assert!(tile_ids.len() > 1000);
+}
+
+#[tokio::test]
+async fn test_extract() {
+    use crate::fetcher::LocalFetcher;
+    use crate::pmtiles::iterate_tiles;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    for z in 0..4 {
+        for x in 0..2_u64.pow(z as u32) {
+            for y in 0..2_u64.pow(z as u32) {
+                writer
+                    .add_tile(z, x, y, format!("{}/{}/{}", z, x, y).as_bytes())
+                    .unwrap();
+            }
+        }
+    }
+    let source = tempfile::NamedTempFile::new().unwrap();
+    writer.finish(&mut source.as_file()).unwrap();
+    let source_path = source.path().to_str().unwrap();
+    let client = LocalFetcher::new();
+
+    async fn extracted_tiles(path: &str, client: &LocalFetcher) -> Vec<(u64, u64, u64)> {
+        let mut tiles = iterate_tiles(path, client, None as Option<&InMemoryCache>)
+            .await
+            .unwrap();
+        let data = std::fs::read(path).unwrap();
+        let mut extracted = Vec::new();
+        while let Some(tile) = tiles.next().await.unwrap() {
+            let contents = &data[tile.offset as usize..(tile.offset + tile.length) as usize];
+            assert_eq!(
+                contents,
+                format!("{}/{}/{}", tile.z, tile.x, tile.y).as_bytes()
+            );
+            extracted.push((tile.z, tile.x, tile.y));
+        }
+        extracted.sort();
+        extracted
+    }
+
+    // North-eastern quarter of the world at zoom levels 1 and 2
+    let output = tempfile::NamedTempFile::new().unwrap();
+    let region = Region::BBox([1., 1., 179., 80.]);
+    let headers = extract(
+        source_path,
+        &client,
+        &region,
+        Some(1),
+        Some(2),
+        &mut output.as_file(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((headers.min_zoom, headers.max_zoom), (1, 2));
+    assert_eq!(
+        extracted_tiles(output.path().to_str().unwrap(), &client).await,
+        vec![(1, 1, 0), (2, 2, 0), (2, 2, 1), (2, 3, 0), (2, 3, 1)]
+    );
+
+    // A small triangle in the south-west only touches a single tile per zoom level
+    let output = tempfile::NamedTempFile::new().unwrap();
+    let region = Region::Polygons(vec![vec![vec![
+        [-100., -30.],
+        [-95., -30.],
+        [-100., -25.],
+        [-100., -30.],
+    ]]]);
+    extract(
+        source_path,
+        &client,
+        &region,
+        None,
+        None,
+        &mut output.as_file(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        extracted_tiles(output.path().to_str().unwrap(), &client).await,
+        vec![(0, 0, 0), (1, 0, 1), (2, 0, 2), (3, 1, 4)]
+    );
+}
@@ -1,16 +1,26 @@
-use super::fileutils::{get_file, get_file_range};
+use super::fileutils::{get_file, get_file_range, list_files};
 
-use crate::s3utils::is_s3_path;
+#[cfg(feature = "http")]
+use crate::httputils::{get_url, get_url_range, is_http_path};
 #[cfg(feature = "s3")]
-use crate::s3utils::{get_object, get_object_range};
+use crate::s3utils::{get_object, get_object_range, is_s3_path, list_objects};
 
+#[cfg(feature = "mmap")]
+use crate::mmaputils::MappedFiles;
 #[cfg(feature = "s3")]
 use aws_sdk_s3 as s3;
 #[cfg(feature = "s3")]
 use aws_sdk_s3::error::ProvideErrorMetadata;
+use bytes::Bytes;
+use fxhash::FxHashMap as HashMap;
 #[cfg(feature = "s3")]
 use s3::Error as S3Error;
+use std::future::Future;
+use std::sync::Mutex;
+#[cfg(feature = "mmap")]
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::watch;
 
 #[derive(Error, Debug)]
 pub enum FetcherError {
@@ -18,10 +28,22 @@ pub enum FetcherError {
     NotFound(),
     #[error("s3 error: {0}")]
     S3Error(String),
+    #[error("http error: {0}")]
+    HttpError(String),
+    #[error("etag mismatch")]
+    EtagMismatch(),
     #[error("{0}")]
     Other(#[from] anyhow::Error),
 }
 
+// Map local file errors so that missing files are reported as not found
+fn local_error(err: anyhow::Error) -> FetcherError {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(io_err) if io_err.kind() == std::io::ErrorKind::NotFound => FetcherError::NotFound(),
+        _ => FetcherError::Other(err),
+    }
+}
+
 #[cfg(feature = "s3")]
 impl From<S3Error> for FetcherError {
     fn from(err: S3Error) -> Self {
@@ -32,6 +54,7 @@ impl From<S3Error> for FetcherError {
             S3Error::NoSuchBucket(_) => FetcherError::NotFound(),
             S3Error::NoSuchKey(_) => FetcherError::NotFound(),
             S3Error::NotFound(_) => FetcherError::NotFound(),
+            _ if err.code() == Some("PreconditionFailed") => FetcherError::EtagMismatch(),
             _ => {
                 tracing::error!("s3 error with code: {:?}", err.code());
                 FetcherError::S3Error("s3 error".into())
@@ -40,17 +63,48 @@ impl From<S3Error> for FetcherError {
     }
 }
 
-pub trait Fetcher {
+// Fail with EtagMismatch if the data was read from a file with a different ETag
+fn match_etag(
+    (data, current): (Bytes, Option<String>),
+    etag: &str,
+) -> Result<(Bytes, Option<String>), FetcherError> {
+    match current {
+        Some(current) if current != etag => Err(FetcherError::EtagMismatch()),
+        current => Ok((data, current)),
+    }
+}
+
+pub trait Fetcher: Sync {
     fn get_data_range(
         &self,
         path: &str,
         offset: usize,
         length: usize,
-    ) -> impl std::future::Future<Output = Result<(Vec<u8>, Option<String>), FetcherError>> + Send;
+    ) -> impl std::future::Future<Output = Result<(Bytes, Option<String>), FetcherError>> + Send;
+    // Read a range only if the file still has the given ETag, like with an If-Match header.
+    // The default compares the ETag returned with the data after reading it.
+    fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> impl std::future::Future<Output = Result<(Bytes, Option<String>), FetcherError>> + Send
+    {
+        async move { match_etag(self.get_data_range(path, offset, length).await?, etag) }
+    }
     fn get_data(
         &self,
         path: &str,
-    ) -> impl std::future::Future<Output = Result<(Vec<u8>, Option<String>), FetcherError>> + Send;
+    ) -> impl std::future::Future<Output = Result<(Bytes, Option<String>), FetcherError>> + Send;
+    // List the paths of the files directly under the given directory or prefix.
+    // Fetchers that cannot list files keep the default, which fails.
+    fn list_paths(
+        &self,
+        _path: &str,
+    ) -> impl std::future::Future<Output = Result<Vec<String>, FetcherError>> + Send {
+        async { Err(anyhow::anyhow!("listing files is not supported").into()) }
+    }
 }
 
 #[cfg(feature = "s3")]
@@ -65,25 +119,49 @@ impl Fetcher for S3Fetcher {
         path: &str,
         offset: usize,
         length: usize,
-    ) -> Result<(Vec<u8>, Option<String>), FetcherError> {
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
         if is_s3_path(path) {
-            get_object_range(path, &self.client, offset, length)
-                .await
-                .map_err(Into::into)
+            get_object_range(path, &self.client, offset, length, None).await
         } else {
             Err(anyhow::anyhow!("invalid S3 path").into())
         }
     }
-    async fn get_data(&self, path: &str) -> Result<(Vec<u8>, Option<String>), FetcherError> {
+    async fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_s3_path(path) {
+            get_object_range(path, &self.client, offset, length, Some(etag)).await
+        } else {
+            Err(anyhow::anyhow!("invalid S3 path").into())
+        }
+    }
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
         if is_s3_path(path) {
             get_object(path, &self.client).await.map_err(Into::into)
         } else {
             Err(anyhow::anyhow!("invalid S3 path").into())
         }
     }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        if is_s3_path(path) {
+            list_objects(path, &self.client).await.map_err(Into::into)
+        } else {
+            Err(anyhow::anyhow!("invalid S3 path").into())
+        }
+    }
 }
 
 pub struct LocalFetcher {}
+impl Default for LocalFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LocalFetcher {
     pub fn new() -> Self {
         LocalFetcher {}
@@ -95,11 +173,68 @@ impl Fetcher for LocalFetcher {
         path: &str,
         offset: usize,
         length: usize,
-    ) -> Result<(Vec<u8>, Option<String>), FetcherError> {
-        Ok((get_file_range(path, offset, length).await?, None))
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        get_file_range(path, offset, length)
+            .await
+            .map_err(local_error)
+    }
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
+        get_file(path).await.map_err(local_error)
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        list_files(path).await.map_err(local_error)
+    }
+}
+
+// Fetcher for local archives that maps each archive into memory once and serves ranges
+// as slices of the mapping. Whole files, such as styles and fonts, are read as usual.
+// Archives must be replaced by renaming a new file over them, as truncating or rewriting
+// a mapped file in place crashes the process when the mapping is read.
+#[cfg(feature = "mmap")]
+pub struct MmapFetcher {
+    files: MappedFiles,
+}
+
+#[cfg(feature = "mmap")]
+impl Default for MmapFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl MmapFetcher {
+    pub fn new() -> Self {
+        Self::with_check_interval(Duration::from_secs(1))
+    }
+    // How often mapped files are checked for changes
+    pub fn with_check_interval(check_interval: Duration) -> Self {
+        MmapFetcher {
+            files: MappedFiles::new(check_interval),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Fetcher for MmapFetcher {
+    async fn get_data_range(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        // Ranges are slices of the mapping, so they are not copied. Like ranged HTTP
+        // requests, ranges reaching past the end of the file are truncated.
+        let (data, etag) = self.files.get(path).map_err(local_error)?;
+        let start = offset.min(data.len());
+        let end = offset.saturating_add(length).min(data.len());
+        Ok((data.slice(start..end), etag))
     }
-    async fn get_data(&self, path: &str) -> Result<(Vec<u8>, Option<String>), FetcherError> {
-        Ok((get_file(path).await?, None))
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
+        get_file(path).await.map_err(local_error)
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        list_files(path).await.map_err(local_error)
     }
 }
 
@@ -115,20 +250,43 @@ impl Fetcher for S3OrLocalFetcher {
         path: &str,
         offset: usize,
         length: usize,
-    ) -> Result<(Vec<u8>, Option<String>), FetcherError> {
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
         if is_s3_path(path) {
-            get_object_range(path, &self.client, offset, length)
+            get_object_range(path, &self.client, offset, length, None).await
+        } else {
+            get_file_range(path, offset, length)
                 .await
-                .map_err(Into::into)
+                .map_err(local_error)
+        }
+    }
+    async fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_s3_path(path) {
+            get_object_range(path, &self.client, offset, length, Some(etag)).await
         } else {
-            Ok((get_file_range(path, offset, length).await?, None))
+            let res = get_file_range(path, offset, length)
+                .await
+                .map_err(local_error)?;
+            match_etag(res, etag)
         }
     }
-    async fn get_data(&self, path: &str) -> Result<(Vec<u8>, Option<String>), FetcherError> {
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
         if is_s3_path(path) {
             get_object(path, &self.client).await.map_err(Into::into)
         } else {
-            Ok((get_file(path).await?, None))
+            get_file(path).await.map_err(local_error)
+        }
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        if is_s3_path(path) {
+            list_objects(path, &self.client).await.map_err(Into::into)
+        } else {
+            list_files(path).await.map_err(local_error)
         }
     }
 }
@@ -139,3 +297,554 @@ impl S3OrLocalFetcher {
         S3OrLocalFetcher { client: s3 }
     }
 }
+
+#[cfg(feature = "http")]
+pub struct HttpFetcher {
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "http")]
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "http")]
+impl HttpFetcher {
+    pub fn new() -> Self {
+        HttpFetcher {
+            client: reqwest::Client::new(),
+        }
+    }
+    pub fn with_client(client: reqwest::Client) -> Self {
+        HttpFetcher { client }
+    }
+}
+
+#[cfg(feature = "http")]
+impl Fetcher for HttpFetcher {
+    async fn get_data_range(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_http_path(path) {
+            get_url_range(path, &self.client, offset, length, None).await
+        } else {
+            Err(anyhow::anyhow!("invalid HTTP path").into())
+        }
+    }
+    async fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_http_path(path) {
+            get_url_range(path, &self.client, offset, length, Some(etag)).await
+        } else {
+            Err(anyhow::anyhow!("invalid HTTP path").into())
+        }
+    }
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_http_path(path) {
+            get_url(path, &self.client).await
+        } else {
+            Err(anyhow::anyhow!("invalid HTTP path").into())
+        }
+    }
+}
+
+// Fetcher for S3, HTTP(S) and local paths, picked by the scheme of each path
+#[cfg(all(feature = "s3", feature = "http"))]
+pub struct CombinedFetcher {
+    s3: S3OrLocalFetcher,
+    http: HttpFetcher,
+    #[cfg(feature = "mmap")]
+    mmap: Option<MmapFetcher>,
+}
+
+#[cfg(all(feature = "s3", feature = "http"))]
+impl CombinedFetcher {
+    pub fn new(s3: s3::Client) -> Self {
+        CombinedFetcher {
+            s3: S3OrLocalFetcher::new(s3),
+            http: HttpFetcher::new(),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        }
+    }
+    // Read ranges of local files through memory mappings
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap(mut self, mmap: MmapFetcher) -> Self {
+        self.mmap = Some(mmap);
+        self
+    }
+    #[cfg(feature = "mmap")]
+    fn mmap(&self, path: &str) -> Option<&MmapFetcher> {
+        self.mmap
+            .as_ref()
+            .filter(|_| !is_http_path(path) && !is_s3_path(path))
+    }
+}
+
+#[cfg(all(feature = "s3", feature = "http"))]
+impl Fetcher for CombinedFetcher {
+    async fn get_data_range(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = self.mmap(path) {
+            return mmap.get_data_range(path, offset, length).await;
+        }
+        if is_http_path(path) {
+            self.http.get_data_range(path, offset, length).await
+        } else {
+            self.s3.get_data_range(path, offset, length).await
+        }
+    }
+    async fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> Result<(Bytes, Option<String>), FetcherError> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = self.mmap(path) {
+            return mmap
+                .get_data_range_if_match(path, offset, length, etag)
+                .await;
+        }
+        if is_http_path(path) {
+            self.http
+                .get_data_range_if_match(path, offset, length, etag)
+                .await
+        } else {
+            self.s3
+                .get_data_range_if_match(path, offset, length, etag)
+                .await
+        }
+    }
+    async fn get_data(&self, path: &str) -> Result<(Bytes, Option<String>), FetcherError> {
+        if is_http_path(path) {
+            self.http.get_data(path).await
+        } else {
+            self.s3.get_data(path).await
+        }
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        if is_http_path(path) {
+            self.http.list_paths(path).await
+        } else {
+            self.s3.list_paths(path).await
+        }
+    }
+}
+
+type RangeResult = Result<(Bytes, Option<String>), FetcherError>;
+// Path, offset, length and the ETag the range is conditional on
+type RangeKey = (String, usize, usize, Option<String>);
+type InFlight = Mutex<HashMap<RangeKey, watch::Receiver<Option<RangeResult>>>>;
+
+impl FetcherError {
+    // Copy of an error to hand to every caller waiting for the same read
+    fn duplicate(&self) -> Self {
+        match self {
+            FetcherError::NotFound() => FetcherError::NotFound(),
+            FetcherError::S3Error(msg) => FetcherError::S3Error(msg.clone()),
+            FetcherError::HttpError(msg) => FetcherError::HttpError(msg.clone()),
+            FetcherError::EtagMismatch() => FetcherError::EtagMismatch(),
+            FetcherError::Other(err) => FetcherError::Other(anyhow::anyhow!("{:#}", err)),
+        }
+    }
+}
+
+// Removes the in-flight entry of a read once it completes or its caller gives up on it
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    key: RangeKey,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+// Wraps a fetcher so that concurrent reads of the same range share a single request.
+// The first caller performs the read and the others wait for its result, which is what
+// happens when many tiles of a cold archive are requested at once and all of them need
+// the same header block and leaf directories. Whole file reads and listings are passed
+// through as they are.
+pub struct CoalescingFetcher<T: Fetcher> {
+    inner: T,
+    in_flight: InFlight,
+}
+
+impl<T: Fetcher> CoalescingFetcher<T> {
+    pub fn new(inner: T) -> Self {
+        CoalescingFetcher {
+            inner,
+            in_flight: Mutex::new(HashMap::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    async fn coalesce<F: Future<Output = RangeResult>>(
+        &self,
+        key: RangeKey,
+        fetch: F,
+    ) -> RangeResult {
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(receiver) => Ok(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    in_flight.insert(key.clone(), receiver);
+                    Err(sender)
+                }
+            }
+        };
+        match waiting {
+            Ok(mut receiver) => {
+                tracing::debug!("waiting for in-flight read of {} at {}", key.0, key.1);
+                let shared =
+                    receiver
+                        .wait_for(Option::is_some)
+                        .await
+                        .ok()
+                        .and_then(|res| match res.as_ref() {
+                            Some(Ok(data)) => Some(Ok(data.clone())),
+                            Some(Err(err)) => Some(Err(err.duplicate())),
+                            None => None,
+                        });
+                match shared {
+                    Some(res) => res,
+                    // The caller performing the read gave up on it, so read the range here
+                    None => fetch.await,
+                }
+            }
+            Err(sender) => {
+                let guard = InFlightGuard {
+                    in_flight: &self.in_flight,
+                    key,
+                };
+                let res = fetch.await;
+                // No new callers can start waiting once the entry is gone
+                drop(guard);
+                if sender.receiver_count() > 0 {
+                    sender.send_replace(Some(match &res {
+                        Ok(data) => Ok(data.clone()),
+                        Err(err) => Err(err.duplicate()),
+                    }));
+                }
+                res
+            }
+        }
+    }
+}
+
+impl<T: Fetcher> Fetcher for CoalescingFetcher<T> {
+    async fn get_data_range(&self, path: &str, offset: usize, length: usize) -> RangeResult {
+        let key = (path.to_string(), offset, length, None);
+        self.coalesce(key, self.inner.get_data_range(path, offset, length))
+            .await
+    }
+    async fn get_data_range_if_match(
+        &self,
+        path: &str,
+        offset: usize,
+        length: usize,
+        etag: &str,
+    ) -> RangeResult {
+        let key = (path.to_string(), offset, length, Some(etag.to_string()));
+        let fetch = self
+            .inner
+            .get_data_range_if_match(path, offset, length, etag);
+        self.coalesce(key, fetch).await
+    }
+    async fn get_data(&self, path: &str) -> RangeResult {
+        self.inner.get_data(path).await
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        self.inner.list_paths(path).await
+    }
+}
+
+// Fetcher for tests that counts the reads of local files, and yields a few times before
+// each of them so that concurrent reads overlap
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CountingFetcher {
+    inner: LocalFetcher,
+    requests: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl CountingFetcher {
+    pub(crate) fn new() -> Self {
+        CountingFetcher::default()
+    }
+
+    pub(crate) fn requests(&self) -> usize {
+        self.requests.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    async fn count(&self) {
+        self.requests
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        for _ in 0..3 {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Fetcher for CountingFetcher {
+    async fn get_data_range(&self, path: &str, offset: usize, length: usize) -> RangeResult {
+        self.count().await;
+        self.inner.get_data_range(path, offset, length).await
+    }
+    async fn get_data(&self, path: &str) -> RangeResult {
+        self.count().await;
+        self.inner.get_data(path).await
+    }
+    async fn list_paths(&self, path: &str) -> Result<Vec<String>, FetcherError> {
+        self.inner.list_paths(path).await
+    }
+}
+
+#[tokio::test]
+async fn test_coalescing_fetcher() {
+    let path = "../../testdata/data/data.pmtiles";
+    let missing = "../../testdata/data/missing.pmtiles";
+    let (expected, etag) = LocalFetcher::new()
+        .get_data_range(path, 1, 4)
+        .await
+        .unwrap();
+    let etag = etag.unwrap();
+
+    let client = CoalescingFetcher::new(CountingFetcher::new());
+    let reads = || client.inner().requests();
+    let (a, b, c) = tokio::join!(
+        client.get_data_range(path, 1, 4),
+        client.get_data_range(path, 1, 4),
+        client.get_data_range(path, 2, 4),
+    );
+    assert_eq!(a.unwrap(), (expected.clone(), Some(etag.clone())));
+    assert_eq!(b.unwrap(), (expected, Some(etag.clone())));
+    assert_eq!(c.unwrap().0, b"Tile".as_slice());
+    assert_eq!(reads(), 2);
+    assert!(client.in_flight.lock().unwrap().is_empty());
+
+    // Reads of the same range conditional on different ETags are kept apart
+    let (a, b) = tokio::join!(
+        client.get_data_range_if_match(path, 1, 4, &etag),
+        client.get_data_range_if_match(path, 1, 4, "other"),
+    );
+    assert!(a.is_ok());
+    assert!(matches!(b, Err(FetcherError::EtagMismatch())));
+    assert_eq!(reads(), 4);
+
+    let (a, b) = tokio::join!(
+        client.get_data_range(missing, 0, 4),
+        client.get_data_range(missing, 0, 4),
+    );
+    assert!(matches!(a, Err(FetcherError::NotFound())));
+    assert!(matches!(b, Err(FetcherError::NotFound())));
+    assert_eq!(reads(), 5);
+
+    // Completed reads are not reused
+    client.get_data_range(path, 1, 4).await.unwrap();
+    assert_eq!(reads(), 6);
+}
+
+#[cfg(feature = "mmap")]
+#[tokio::test]
+async fn test_mmap_fetcher() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("archive.pmtiles");
+    let path = path.to_str().unwrap();
+    std::fs::copy("../../testdata/data/data.pmtiles", path).unwrap();
+    let client = MmapFetcher::with_check_interval(Duration::ZERO);
+
+    let (header, etag) = client.get_data_range(path, 0, 7).await.unwrap();
+    assert_eq!(header, b"PMTiles".as_slice());
+    let (local, local_etag) = LocalFetcher::new()
+        .get_data_range(path, 0, 7)
+        .await
+        .unwrap();
+    assert_eq!(header, local);
+    assert_eq!(etag, local_etag);
+    // Reads of an unchanged file share the mapping
+    let (again, _) = client.get_data_range(path, 0, 7).await.unwrap();
+    assert_eq!(header.as_ptr(), again.as_ptr());
+
+    let tile = crate::get_tile(
+        14,
+        9325,
+        4732,
+        path,
+        &client,
+        None as Option<&crate::cache::InMemoryCache>,
+    )
+    .await
+    .unwrap();
+    assert_eq!(tile.len(), 78408);
+
+    let len = std::fs::metadata(path).unwrap().len() as usize;
+    let (data, _) = client.get_data_range(path, len - 4, 16).await.unwrap();
+    assert_eq!(data.len(), 4);
+    let (data, _) = client.get_data_range(path, len + 1, 16).await.unwrap();
+    assert!(data.is_empty());
+    let res = client
+        .get_data_range_if_match(path, 0, 7, "\"other\"")
+        .await;
+    assert!(matches!(res, Err(FetcherError::EtagMismatch())));
+
+    // Replacing the file maps the new file, while earlier slices stay valid
+    let replacement = dir.path().join("replacement");
+    std::fs::write(&replacement, b"replaced").unwrap();
+    std::fs::rename(&replacement, path).unwrap();
+    let (data, new_etag) = client.get_data_range(path, 0, 8).await.unwrap();
+    assert_eq!(data, b"replaced".as_slice());
+    assert_ne!(new_etag, etag);
+    assert_eq!(header, b"PMTiles".as_slice());
+
+    let missing = client
+        .get_data_range(&format!("{}.missing", path), 0, 7)
+        .await;
+    assert!(matches!(missing, Err(FetcherError::NotFound())));
+}
+
+// Serve files under the given directory over HTTP with support for single byte ranges.
+// Files under /norange/ are served whole, like by servers without range support.
+#[cfg(all(test, feature = "http"))]
+async fn serve_test_files(root: &'static str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let range = request.lines().find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    if !name.eq_ignore_ascii_case("range") {
+                        return None;
+                    }
+                    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+                    Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                });
+                let range = range.filter(|_| !path.starts_with("/norange/"));
+                let path = path.trim_start_matches("/norange");
+                // Report a range starting one byte later than the one sent
+                let shift = path.starts_with("/shifted/") as usize;
+                let path = path.trim_start_matches("/shifted");
+                let (head, body) = match std::fs::read(format!("{}{}", root, path)) {
+                    Ok(data) => match range {
+                        Some((start, _)) if start >= data.len() => {
+                            ("416 Range Not Satisfiable".to_string(), Vec::new())
+                        }
+                        Some((start, end)) => {
+                            let end = end.min(data.len() - 1);
+                            (
+                                format!(
+                                    "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                                    start + shift,
+                                    end,
+                                    data.len()
+                                ),
+                                data[start..=end].to_vec(),
+                            )
+                        }
+                        None => ("200 OK".to_string(), data),
+                    },
+                    Err(_) => ("404 Not Found".to_string(), Vec::new()),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nETag: \"test-etag\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    head,
+                    body.len()
+                );
+                // Clients may close the connection without reading the whole body
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            });
+        }
+    });
+    format!("http://{}", addr)
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_http_fetcher() {
+    let base_url = serve_test_files("../../testdata/data").await;
+    let client = HttpFetcher::new();
+    let path = format!("{}/data.pmtiles", base_url);
+
+    let (data, etag) = client.get_data_range(&path, 0, 7).await.unwrap();
+    assert_eq!(data, &b"PMTiles"[..]);
+    assert_eq!(etag, Some("\"test-etag\"".into()));
+
+    let (full, _) = client.get_data(&path).await.unwrap();
+    let (data, _) = client
+        .get_data_range(&path, full.len() - 4, 16)
+        .await
+        .unwrap();
+    assert_eq!(data, full[full.len() - 4..]);
+    let (data, _) = client
+        .get_data_range(&path, full.len() + 1, 16)
+        .await
+        .unwrap();
+    assert!(data.is_empty());
+
+    let no_range = client
+        .get_data_range(&format!("{}/norange/data.pmtiles", base_url), 0, 7)
+        .await;
+    assert!(matches!(no_range, Err(FetcherError::HttpError(_))));
+    let shifted = client
+        .get_data_range(&format!("{}/shifted/data.pmtiles", base_url), 0, 7)
+        .await;
+    assert!(matches!(shifted, Err(FetcherError::HttpError(_))));
+
+    let missing = client
+        .get_data(&format!("{}/missing.pmtiles", base_url))
+        .await;
+    assert!(matches!(missing, Err(FetcherError::NotFound())));
+
+    let tile = crate::get_tile(
+        14,
+        9325,
+        4732,
+        &path,
+        &client,
+        None as Option<&crate::cache::InMemoryCache>,
+    )
+    .await
+    .unwrap();
+    assert_eq!(tile.len(), 78408);
+}
@@ -1,19 +1,61 @@
+use bytes::Bytes;
+use std::fs::Metadata;
 use std::io::SeekFrom;
+use std::time::UNIX_EPOCH;
 
 use tokio::{
-    fs::{read, File},
+    fs::{read_dir, File},
     io::{AsyncReadExt, AsyncSeekExt},
 };
 
-pub async fn get_file_range(path: &str, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+// Local files have no ETag, so one is derived from the modification time and size
+pub fn metadata_etag(metadata: &Metadata) -> anyhow::Result<String> {
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!(
+        "\"{:x}-{:x}\"",
+        modified.as_nanos(),
+        metadata.len()
+    ))
+}
+
+async fn file_etag(file: &File) -> anyhow::Result<String> {
+    metadata_etag(&file.metadata().await?)
+}
+
+pub async fn get_file_range(
+    path: &str,
+    offset: usize,
+    length: usize,
+) -> anyhow::Result<(Bytes, Option<String>)> {
     let mut file = File::open(path).await?;
+    let etag = file_etag(&file).await.ok();
     file.seek(SeekFrom::Start(offset as u64)).await?;
-    let mut buffer: Vec<u8> = vec![0; length];
-    file.read_exact(&mut buffer).await?;
-    Ok(buffer)
+    // Like ranged HTTP requests, ranges reaching past the end of the file are truncated
+    let mut buffer: Vec<u8> = Vec::with_capacity(length);
+    file.take(length as u64).read_to_end(&mut buffer).await?;
+    Ok((buffer.into(), etag))
+}
+
+pub async fn get_file(path: &str) -> anyhow::Result<(Bytes, Option<String>)> {
+    let mut file = File::open(path).await?;
+    let etag = file_etag(&file).await.ok();
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).await?;
+    Ok((buffer.into(), etag))
 }
 
-pub async fn get_file(path: &str) -> anyhow::Result<Vec<u8>> {
-    let buffer = read(&path).await?;
-    Ok(buffer)
+pub async fn list_files(path: &str) -> anyhow::Result<Vec<String>> {
+    let mut entries = read_dir(path).await?;
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            let entry_path = entry.path();
+            let stringified_path = entry_path
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("invalid path {}", entry_path.to_string_lossy()))?;
+            paths.push(stringified_path.to_string());
+        }
+    }
+    paths.sort();
+    Ok(paths)
 }
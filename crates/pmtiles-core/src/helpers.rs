@@ -1,14 +1,17 @@
 use super::{
-    cache::Cache,
+    cache::{Cache, CacheError},
     compress::decompress,
     fetcher::Fetcher,
-    models::Headers,
+    models::{is_v2_header, Headers, HEADER_SIZE_BYTES},
     pmtiles::PMTilesError,
     utils::{rotate, TILES_PER_LEVEL},
+    v2,
 };
 use crate::compress::Compression;
-use crate::models::TileEntry;
-use crate::utils::read_varint;
+use crate::models::{Directory, TileEntry};
+use crate::utils::{read_varint, write_varint};
+use bytes::Bytes;
+use std::sync::Arc;
 
 pub fn find_tile(
     z: u64,
@@ -18,6 +21,9 @@ pub fn find_tile(
 ) -> anyhow::Result<&TileEntry, PMTilesError> {
     let tile_id = zxy_to_tile_id(z, x, y)?;
 
+    if entries.is_empty() {
+        return Err(PMTilesError::OutOfBounds());
+    }
     let mut m = 0;
     let mut n = entries.len() - 1;
     while m <= n {
@@ -26,6 +32,10 @@ pub fn find_tile(
         if cmp > 0 {
             m = k + 1;
         } else if cmp < 0 {
+            // The tile comes before the first entry of the directory
+            if k == 0 {
+                return Err(PMTilesError::OutOfBounds());
+            }
             n = k - 1
         } else {
             return Ok(&entries[k]);
@@ -41,16 +51,22 @@ pub fn find_tile(
     Err(PMTilesError::OutOfBounds())
 }
 
-fn decode_entries(data: &[u8]) -> anyhow::Result<Vec<TileEntry>> {
-    let mut pos = 0 as usize;
+pub(crate) fn decode_entries(data: &[u8]) -> anyhow::Result<Vec<TileEntry>> {
+    let mut pos = 0_usize;
     let num_entries = read_varint(data, &mut pos)?;
+    // Every entry takes at least one byte, which bounds the allocation for corrupt data
+    if num_entries > data.len() as u64 {
+        anyhow::bail!("directory entry count exceeds directory size");
+    }
     let mut entries = Vec::<TileEntry>::with_capacity(num_entries as usize);
-    let mut last_id = 0;
+    let mut last_id: u64 = 0;
     for _ in 0..num_entries {
         let val = read_varint(data, &mut pos)?;
-        let tile_id = last_id + val;
+        let tile_id = last_id
+            .checked_add(val)
+            .ok_or_else(|| anyhow::anyhow!("tile id overflow"))?;
         entries.push(TileEntry {
-            tile_id: last_id + val,
+            tile_id,
             offset: 0,
             length: 0,
             run_length: 1,
@@ -58,20 +74,21 @@ fn decode_entries(data: &[u8]) -> anyhow::Result<Vec<TileEntry>> {
         last_id = tile_id;
     }
 
-    for i in 0..num_entries as usize {
-        let val = read_varint(data, &mut pos)?;
-        entries[i].run_length = val;
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(data, &mut pos)?;
     }
 
-    for i in 0..num_entries as usize {
-        let val = read_varint(data, &mut pos)?;
-        entries[i].length = val;
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(data, &mut pos)?;
     }
 
     for i in 0..num_entries as usize {
         let val = read_varint(data, &mut pos)?;
         if val == 0 && i > 0 {
-            entries[i].offset = entries[i - 1].offset + entries[i - 1].length;
+            entries[i].offset = entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .ok_or_else(|| anyhow::anyhow!("tile offset overflow"))?;
         } else if val == 0 && i == 0 {
             entries[i].offset = 0;
         } else {
@@ -81,64 +98,186 @@ fn decode_entries(data: &[u8]) -> anyhow::Result<Vec<TileEntry>> {
     Ok(entries)
 }
 
-pub fn get_entries(data: &[u8], compression: Compression) -> anyhow::Result<Vec<TileEntry>> {
-    let decompressed = decompress(&data, compression)?;
-    let data = decompressed.into_iter().collect::<Vec<u8>>();
+pub fn encode_entries(entries: &[TileEntry]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(entries.len() * 4 + 8);
+    write_varint(&mut data, entries.len() as u64);
+
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut data, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+
+    for entry in entries {
+        write_varint(&mut data, entry.run_length);
+    }
+
+    for entry in entries {
+        write_varint(&mut data, entry.length);
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        // Offsets directly following the previous entry are encoded as zero
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length {
+            write_varint(&mut data, 0);
+        } else {
+            write_varint(&mut data, entry.offset + 1);
+        }
+    }
+    data
+}
+
+pub fn get_entries(data: Bytes, compression: Compression) -> anyhow::Result<Vec<TileEntry>> {
+    let data = decompress(data, compression)?;
     let entries = decode_entries(&data)?;
     Ok(entries)
 }
 
-const HEADER_SIZE_BYTES: usize = 127;
+pub(crate) fn log_cache_set(key: &str, res: Result<(), CacheError>) {
+    if let Err(err) = res {
+        tracing::warn!("failed to cache key {} with {}", key, err);
+    } else {
+        tracing::debug!("cached key {}", key);
+    }
+}
+
+// Headers are cached in the version 3 layout, prefixed with the spec version of the
+// archive and followed by the ETag of the archive, so that the ETag is evicted and
+// invalidated along with the headers. Headers describing version 2 archives are laid out
+// as version 3 headers, and only the prefix tells them apart.
+fn encode_cached_headers(headers: &Headers) -> anyhow::Result<Bytes> {
+    let mut v3_headers = headers.clone();
+    v3_headers.spec_version = 3;
+    let mut data = vec![headers.spec_version];
+    data.extend(v3_headers.to_bytes()?);
+    if let Some(etag) = &headers.etag {
+        data.extend(etag.as_bytes());
+    }
+    Ok(data.into())
+}
+
+fn decode_cached_headers(data: &[u8]) -> anyhow::Result<Headers> {
+    let (spec_version, data) = data
+        .split_first()
+        .filter(|(_, data)| data.len() >= HEADER_SIZE_BYTES)
+        .ok_or_else(|| anyhow::anyhow!("invalid cached headers"))?;
+    let (header_data, etag) = data.split_at(HEADER_SIZE_BYTES);
+    let mut headers = Headers::from_bytes(header_data)?;
+    headers.spec_version = *spec_version;
+    if !etag.is_empty() {
+        headers.etag = Some(String::from_utf8(etag.to_vec())?);
+    }
+    Ok(headers)
+}
 
-pub async fn get_headers<T: Fetcher, C: Cache>(
+// Get the headers and the decoded root directory of an archive. The header bytes are
+// cached by archive path along with the ETag, and the root directory by {path}|root.
+pub async fn get_headers<T: Fetcher, C: Cache + ?Sized>(
     path: &str,
     client: &T,
     cache: Option<&C>,
-) -> Result<(Headers, Vec<TileEntry>), PMTilesError> {
-    let cache_hit = {
-        match &cache {
-            Some(cache) => cache.get(&path),
-            None => None,
+) -> Result<(Headers, Arc<Directory>), PMTilesError> {
+    let root_key = format!("{}|root", path);
+    if let Some(cache) = cache {
+        if let (Some(header_data), Some(root)) = (cache.get(path), cache.get_directory(&root_key)) {
+            match decode_cached_headers(&header_data) {
+                Ok(headers) => {
+                    tracing::debug!("cache hit for key {}", path);
+                    return Ok((headers, root));
+                }
+                Err(err) => tracing::warn!("failed to decode cached headers {}: {}", path, err),
+            }
+        }
+    }
+
+    let (raw_data, etag) = client.get_data_range(path, 0, 16384).await?;
+    let (headers, root) = if is_v2_header(&raw_data) {
+        let (mut headers, root) =
+            v2::read_header_block(path, raw_data, etag.as_deref(), client).await?;
+        headers.etag = etag;
+        (headers, Arc::new(root))
+    } else {
+        if raw_data.len() < HEADER_SIZE_BYTES {
+            tracing::error!("{} tile dataset does not contain valid headers", path);
+            return Err(PMTilesError::Other(anyhow::anyhow!(
+                "tile dataset does not contain valid headers"
+            )));
+        }
+        let mut headers = Headers::from_bytes(&raw_data[..HEADER_SIZE_BYTES])?;
+        headers.etag = etag;
+        let root_range = headers.root_directory_offset as usize
+            ..(headers.root_directory_offset + headers.root_directory_length) as usize;
+        if root_range.end > raw_data.len() {
+            return Err(anyhow::anyhow!("root directory does not fit in the header block").into());
         }
+        let root = Arc::new(Directory::from(get_entries(
+            raw_data.slice(root_range),
+            Compression::from(headers.internal_compression),
+        )?));
+        (headers, root)
     };
 
-    let raw_data = match cache_hit {
-        Some(cached) => {
-            tracing::debug!("cache hit for key {}", path);
-            cached
+    if let Some(cache) = cache {
+        match encode_cached_headers(&headers) {
+            Ok(header_data) => log_cache_set(path, cache.set(path, header_data)),
+            Err(err) => tracing::warn!("failed to encode headers of {}: {}", path, err),
+        }
+        log_cache_set(&root_key, cache.set_directory(&root_key, root.clone()));
+    }
+    Ok((headers, root))
+}
+
+// Read a range of an archive, making sure it still has the ETag the headers were read with
+pub async fn get_archive_range<T: Fetcher>(
+    path: &str,
+    headers: &Headers,
+    offset: u64,
+    length: u64,
+    client: &T,
+) -> Result<Bytes, PMTilesError> {
+    let (data, _) = match &headers.etag {
+        Some(etag) => {
+            client
+                .get_data_range_if_match(path, offset as usize, length as usize, etag)
+                .await?
         }
         None => {
-            let (data, _) = client.get_data_range(path, 0, 16384).await?;
-            if let Some(cache) = cache {
-                let res = cache.set(path, &data);
-                if let Err(err) = res {
-                    tracing::warn!("failed to cache key {} with {}", path, err);
-                } else {
-                    tracing::debug!("cached key {}", path);
-                }
-            };
-            data
+            client
+                .get_data_range(path, offset as usize, length as usize)
+                .await?
         }
     };
+    Ok(data)
+}
 
-    if raw_data.len() < HEADER_SIZE_BYTES {
-        tracing::error!("{} tile dataset does not contain valid headers", path);
-        return Err(PMTilesError::Other(anyhow::anyhow!(
-            "tile dataset does not contain valid headers"
-        )));
-    }
-    let headers = Headers::from_bytes(&raw_data[..HEADER_SIZE_BYTES])?;
-    let root_dir_data = &raw_data[headers.root_directory_offset as usize
-        ..(&headers.root_directory_offset + &headers.root_directory_length) as usize];
-
-    let entries = get_entries(
-        &root_dir_data,
-        Compression::from(headers.internal_compression),
-    )?;
-    Ok((headers, entries))
+// Fetch and decode a leaf directory, caching it by archive path and position
+pub async fn get_leaf_directory<T: Fetcher, C: Cache + ?Sized>(
+    path: &str,
+    headers: &Headers,
+    offset: u64,
+    length: u64,
+    client: &T,
+    cache: Option<&C>,
+) -> Result<Arc<Directory>, PMTilesError> {
+    let key = format!("{}|leaf|{}|{}", path, offset, length);
+    if let Some(cached) = cache.and_then(|cache| cache.get_directory(&key)) {
+        tracing::debug!("cache hit for key {}", key);
+        return Ok(cached);
+    }
+    let data = get_archive_range(path, headers, offset, length, client).await?;
+    let leaf = if headers.spec_version == 2 {
+        Arc::new(v2::decode_directory(&data)?)
+    } else {
+        let compression = Compression::from(headers.internal_compression);
+        Arc::new(Directory::from(get_entries(data, compression)?))
+    };
+    if let Some(cache) = cache {
+        log_cache_set(&key, cache.set_directory(&key, leaf.clone()));
+    }
+    Ok(leaf)
 }
 
-fn zxy_to_tile_id(z: u64, x: u64, y: u64) -> anyhow::Result<u64> {
+pub fn zxy_to_tile_id(z: u64, x: u64, y: u64) -> anyhow::Result<u64> {
     if z > 26 {
         anyhow::bail!("zoom level exceeds maximum")
     }
@@ -146,8 +285,8 @@ fn zxy_to_tile_id(z: u64, x: u64, y: u64) -> anyhow::Result<u64> {
         anyhow::bail!("x or y outsize zoom level bounds")
     }
 
-    let mut tmp_x: i64 = x.clone().try_into()?;
-    let mut tmp_y: i64 = y.clone().try_into()?;
+    let mut tmp_x: i64 = x.try_into()?;
+    let mut tmp_y: i64 = y.try_into()?;
 
     let acc = TILES_PER_LEVEL[z as usize];
     let n = 2_i64.pow(z as u32);
@@ -158,8 +297,85 @@ fn zxy_to_tile_id(z: u64, x: u64, y: u64) -> anyhow::Result<u64> {
         let ry = if (tmp_y & s) > 0 { 1 } else { 0 };
         d += s * s * ((3_i64 * rx) ^ ry);
         rotate(s, &mut tmp_x, &mut tmp_y, rx, ry);
-        s = s / 2;
+        s /= 2;
     }
 
     Ok(acc + d as u64)
 }
+
+pub fn tile_id_to_zxy(tile_id: u64) -> anyhow::Result<(u64, u64, u64)> {
+    let z = TILES_PER_LEVEL
+        .iter()
+        .rposition(|acc| *acc <= tile_id)
+        .unwrap_or(0);
+    let n = 2_i64.pow(z as u32);
+    let mut d: i64 = (tile_id - TILES_PER_LEVEL[z]).try_into()?;
+    if d >= n * n {
+        anyhow::bail!("tile id exceeds maximum zoom level")
+    }
+
+    let mut tmp_x = 0;
+    let mut tmp_y = 0;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (d / 2);
+        let ry = 1 & (d ^ rx);
+        rotate(s, &mut tmp_x, &mut tmp_y, rx, ry);
+        tmp_x += s * rx;
+        tmp_y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+
+    Ok((z as u64, tmp_x as u64, tmp_y as u64))
+}
+
+#[cfg(test)]
+#[test]
+fn test_encode_entries() {
+    let entries = vec![
+        TileEntry {
+            tile_id: 0,
+            offset: 0,
+            length: 10,
+            run_length: 1,
+        },
+        TileEntry {
+            tile_id: 1,
+            offset: 10,
+            length: 20,
+            run_length: 3,
+        },
+        TileEntry {
+            tile_id: 1000,
+            offset: 0,
+            length: 10,
+            run_length: 1,
+        },
+        TileEntry {
+            tile_id: 5000,
+            offset: 4096,
+            length: 16384,
+            run_length: 0,
+        },
+    ];
+    let decoded = decode_entries(&encode_entries(&entries)).unwrap();
+    assert_eq!(decoded, entries);
+}
+
+#[cfg(test)]
+#[test]
+fn test_tile_id_to_zxy() {
+    for z in 0..6 {
+        for x in 0..2_u64.pow(z as u32) {
+            for y in 0..2_u64.pow(z as u32) {
+                let tile_id = zxy_to_tile_id(z, x, y).unwrap();
+                assert_eq!(tile_id_to_zxy(tile_id).unwrap(), (z, x, y));
+            }
+        }
+    }
+    let max = 2_u64.pow(26) - 1;
+    let tile_id = zxy_to_tile_id(26, max, max - 1).unwrap();
+    assert_eq!(tile_id_to_zxy(tile_id).unwrap(), (26, max, max - 1));
+    assert!(tile_id_to_zxy(1 << 60).is_err());
+}
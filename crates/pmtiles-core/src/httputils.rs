@@ -0,0 +1,168 @@
+#[cfg(feature = "http")]
+use super::fetcher::FetcherError;
+#[cfg(feature = "http")]
+use bytes::Bytes;
+#[cfg(feature = "http")]
+use reqwest::{header, Client, Response, StatusCode};
+
+// Check whether path is an HTTP(S) url
+pub fn is_http_path(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+#[cfg(feature = "http")]
+fn etag_from_response(res: &Response) -> Option<String> {
+    res.headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(Into::into)
+}
+
+#[cfg(feature = "http")]
+fn check_status(url: &str, status: StatusCode) -> Result<(), FetcherError> {
+    if status == StatusCode::NOT_FOUND {
+        return Err(FetcherError::NotFound());
+    }
+    if !status.is_success() {
+        tracing::error!("request to {} failed with status {}", url, status);
+        return Err(FetcherError::HttpError(format!(
+            "unexpected status {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+// Parse a Content-Range value such as "bytes 0-99/1000" into the first and last byte
+// and the total length, if known
+#[cfg(feature = "http")]
+fn parse_content_range(value: &str) -> Option<(usize, usize, Option<usize>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse::<usize>().ok()?),
+    };
+    (start <= end).then_some((start, end, total))
+}
+
+// Check that a partial response holds the requested range, which it may only cut short
+// at the end of the file, and return the length of the range
+#[cfg(feature = "http")]
+fn check_content_range(
+    url: &str,
+    res: &Response,
+    offset: usize,
+    length: usize,
+) -> Result<usize, FetcherError> {
+    let content_range = res
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok());
+    let requested_end = offset + length - 1;
+    content_range
+        .and_then(parse_content_range)
+        .filter(|&(start, end, total)| {
+            start == offset
+                && end <= requested_end
+                && (end == requested_end || total.is_none_or(|total| total == end + 1))
+        })
+        .map(|(start, end, _)| end - start + 1)
+        .ok_or_else(|| {
+            tracing::error!(
+                "{} responded to the range {}-{} with content range {:?}",
+                url,
+                offset,
+                requested_end,
+                content_range
+            );
+            FetcherError::HttpError(format!(
+                "unexpected content range {}",
+                content_range.unwrap_or("none")
+            ))
+        })
+}
+
+#[cfg(feature = "http")]
+pub async fn get_url_range(
+    url: &str,
+    client: &Client,
+    offset: usize,
+    length: usize,
+    if_match: Option<&str>,
+) -> Result<(Bytes, Option<String>), FetcherError> {
+    tracing::debug!(
+        "get_url_range url={}, offset={}, length={}",
+        url,
+        offset,
+        length
+    );
+    if length == 0 {
+        return Ok((Bytes::new(), None));
+    }
+    let mut req = client.get(url).header(
+        header::RANGE,
+        format!("bytes={}-{}", offset, offset + length - 1),
+    );
+    if let Some(etag) = if_match {
+        req = req.header(header::IF_MATCH, etag);
+    }
+    let res = req
+        .send()
+        .await
+        .map_err(|err| FetcherError::HttpError(err.to_string()))?;
+    let status = res.status();
+    if status == StatusCode::PRECONDITION_FAILED {
+        return Err(FetcherError::EtagMismatch());
+    }
+    // The range starts past the end of the file
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok((Bytes::new(), etag_from_response(&res)));
+    }
+    check_status(url, status)?;
+    // Servers without range support respond with the whole file, which is not downloaded
+    // for every read of a directory or a tile
+    if status != StatusCode::PARTIAL_CONTENT {
+        tracing::error!(
+            "{} responded to a range request with status {}",
+            url,
+            status
+        );
+        return Err(FetcherError::HttpError(format!(
+            "range requests are not supported, got status {}",
+            status
+        )));
+    }
+    let expected_length = check_content_range(url, &res, offset, length)?;
+    let etag = etag_from_response(&res);
+    let data = res
+        .bytes()
+        .await
+        .map_err(|err| FetcherError::HttpError(err.to_string()))?;
+    if data.len() != expected_length {
+        return Err(FetcherError::HttpError(format!(
+            "expected {} bytes, got {}",
+            expected_length,
+            data.len()
+        )));
+    }
+    Ok((data, etag))
+}
+
+#[cfg(feature = "http")]
+pub async fn get_url(url: &str, client: &Client) -> Result<(Bytes, Option<String>), FetcherError> {
+    tracing::debug!("get_url url={}", url);
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| FetcherError::HttpError(err.to_string()))?;
+    check_status(url, res.status())?;
+    let etag = etag_from_response(&res);
+    let data = res
+        .bytes()
+        .await
+        .map_err(|err| FetcherError::HttpError(err.to_string()))?;
+    Ok((data, etag))
+}
@@ -1,11 +1,22 @@
-mod compress;
+pub mod compress;
+pub mod extract;
 mod helpers;
+pub mod httputils;
 pub mod models;
 mod pmtiles;
 pub mod s3utils;
 mod utils;
+pub use helpers::{tile_id_to_zxy, zxy_to_tile_id};
 pub use pmtiles::PMTilesError;
-pub use pmtiles::{get_metadata, get_tile};
+pub use pmtiles::{
+    get_metadata, get_raw_tile, get_tile, get_tile_type, iterate_tiles, tile_etag, TileIterator,
+};
 pub mod cache;
 pub mod fetcher;
 mod fileutils;
+pub use fileutils::metadata_etag;
+#[cfg(feature = "mmap")]
+mod mmaputils;
+pub mod v2;
+pub mod verify;
+pub mod writer;
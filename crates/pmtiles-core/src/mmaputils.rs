@@ -0,0 +1,91 @@
+use crate::fileutils::metadata_etag;
+use bytes::Bytes;
+use fxhash::FxHashMap as HashMap;
+use memmap2::Mmap;
+use std::fs::File;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// A file mapped into memory, along with the ETag of the file it was mapped from
+struct MappedFile {
+    data: Bytes,
+    etag: Option<String>,
+    checked: Instant,
+}
+
+// Local files mapped into memory once and shared by all reads. Whether a file has changed
+// is checked at most once per check interval, and a changed file is mapped again. Reads
+// holding slices of the previous mapping keep it alive until they are done with it, so
+// mapped files must only be replaced by renaming a new file over them.
+pub struct MappedFiles {
+    files: RwLock<HashMap<String, MappedFile>>,
+    check_interval: Duration,
+}
+
+// Times a file is mapped again when it changes while being mapped
+const MAX_MAP_ATTEMPTS: usize = 3;
+
+// Map a file into memory along with the ETag of the mapped version. The metadata of the
+// opened file is compared before and after mapping it, so that the ETag describes the
+// contents that were mapped.
+fn map_file(path: &str) -> anyhow::Result<(Bytes, Option<String>)> {
+    for _ in 0..MAX_MAP_ATTEMPTS {
+        let file = File::open(path)?;
+        let before = file.metadata()?;
+        let data = if before.len() == 0 {
+            Bytes::new()
+        } else {
+            // SAFETY: the mapping is only read, but its contents change if the file is
+            // modified in place, and reading past the end of a truncated file raises
+            // SIGBUS. Archives must therefore be replaced by writing a new file and
+            // renaming it over the old one, which leaves the mapped file intact.
+            let mmap = unsafe { Mmap::map(&file)? };
+            Bytes::from_owner(mmap)
+        };
+        let after = file.metadata()?;
+        let etag = metadata_etag(&before).ok();
+        if etag.is_some() && etag == metadata_etag(&after).ok() && after.len() == data.len() as u64
+        {
+            return Ok((data, etag));
+        }
+        tracing::debug!("{} changed while being mapped", path);
+    }
+    anyhow::bail!("{} kept changing while being mapped", path)
+}
+
+impl MappedFiles {
+    pub fn new(check_interval: Duration) -> Self {
+        MappedFiles {
+            files: RwLock::new(HashMap::default()),
+            check_interval,
+        }
+    }
+
+    // Contents of a file and its ETag, mapping the file if it is new or has changed
+    pub fn get(&self, path: &str) -> anyhow::Result<(Bytes, Option<String>)> {
+        if let Some(file) = self.files.read().unwrap().get(path) {
+            if file.checked.elapsed() < self.check_interval {
+                return Ok((file.data.clone(), file.etag.clone()));
+            }
+        }
+        let etag = metadata_etag(&std::fs::metadata(path)?).ok();
+        let mut files = self.files.write().unwrap();
+        if let Some(file) = files.get_mut(path) {
+            if file.etag.is_some() && file.etag == etag {
+                file.checked = Instant::now();
+                return Ok((file.data.clone(), etag));
+            }
+        }
+        tracing::debug!("mapping {} into memory", path);
+        let (data, etag) = map_file(path)?;
+        files.insert(
+            path.into(),
+            MappedFile {
+                data: data.clone(),
+                etag: etag.clone(),
+                checked: Instant::now(),
+            },
+        );
+        Ok((data, etag))
+    }
+}
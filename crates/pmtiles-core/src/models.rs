@@ -1,7 +1,92 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+use crate::compress::Compression;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
 use std::io::Cursor;
+use std::ops::Deref;
 
-#[derive(Debug)]
+pub const HEADER_SIZE_BYTES: usize = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileType {
+    Unknown,
+    Mvt,
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl From<u8> for TileType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => TileType::Mvt,
+            2 => TileType::Png,
+            3 => TileType::Jpeg,
+            4 => TileType::Webp,
+            5 => TileType::Avif,
+            _ => TileType::Unknown,
+        }
+    }
+}
+
+impl From<TileType> for u8 {
+    fn from(value: TileType) -> Self {
+        match value {
+            TileType::Unknown => 0,
+            TileType::Mvt => 1,
+            TileType::Png => 2,
+            TileType::Jpeg => 3,
+            TileType::Webp => 4,
+            TileType::Avif => 5,
+        }
+    }
+}
+
+impl TileType {
+    // Tile type of a format name as used in MBTiles and PMTiles v2 metadata
+    pub fn from_format(format: &str) -> Self {
+        match format {
+            "pbf" | "mvt" => TileType::Mvt,
+            "png" => TileType::Png,
+            "jpg" | "jpeg" => TileType::Jpeg,
+            "webp" => TileType::Webp,
+            "avif" => TileType::Avif,
+            _ => TileType::Unknown,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TileType::Mvt => "application/x-protobuf",
+            TileType::Png => "image/png",
+            TileType::Jpeg => "image/jpeg",
+            TileType::Webp => "image/webp",
+            TileType::Avif => "image/avif",
+            TileType::Unknown => "application/octet-stream",
+        }
+    }
+
+    // File extension of the tiles in TileJSON urls
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            TileType::Mvt => Some("pbf"),
+            TileType::Png => Some("png"),
+            TileType::Jpeg => Some("jpg"),
+            TileType::Webp => Some("webp"),
+            TileType::Avif => Some("avif"),
+            TileType::Unknown => None,
+        }
+    }
+
+    pub fn is_raster(&self) -> bool {
+        matches!(
+            self,
+            TileType::Png | TileType::Jpeg | TileType::Webp | TileType::Avif
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Headers {
     pub spec_version: u8,
     pub root_directory_offset: u64,
@@ -31,14 +116,22 @@ pub struct Headers {
     pub etag: Option<String>,
 }
 
+// Version 2 archives start with "PM" followed by the version as a 16-bit integer
+pub fn is_v2_header(data: &[u8]) -> bool {
+    data.starts_with(&[0x50, 0x4d, 0x02, 0x00])
+}
+
 impl Headers {
-    pub fn from_bytes<'a>(v: &'a [u8]) -> anyhow::Result<Self> {
-        let mut rdr = Cursor::new(v);
-        if rdr.read_u16::<LittleEndian>()? != 0x4d50 {
+    pub fn from_bytes(v: &[u8]) -> anyhow::Result<Self> {
+        if is_v2_header(v) {
+            anyhow::bail!("pmtiles version 2 archives have a different header layout");
+        }
+        if !v.starts_with(b"PMTiles") {
             return Err(anyhow::anyhow!(format!(
                 "wrong magic number for pmtiles archive - input file is likely not pmtiles",
             )));
         }
+        let mut rdr = Cursor::new(v);
         rdr.set_position(7);
         let spec_version = rdr.read_u8()?;
         if spec_version != 3 {
@@ -101,11 +194,85 @@ impl Headers {
             etag: None,
         })
     }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE_BYTES);
+        buf.extend_from_slice(b"PMTiles");
+        buf.write_u8(self.spec_version)?;
+        buf.write_u64::<LittleEndian>(self.root_directory_offset)?;
+        buf.write_u64::<LittleEndian>(self.root_directory_length)?;
+        buf.write_u64::<LittleEndian>(self.json_metadata_offset)?;
+        buf.write_u64::<LittleEndian>(self.json_metadata_length)?;
+        buf.write_u64::<LittleEndian>(self.leaf_directory_offset)?;
+        buf.write_u64::<LittleEndian>(self.leaf_directory_length)?;
+        buf.write_u64::<LittleEndian>(self.tile_data_offset)?;
+        buf.write_u64::<LittleEndian>(self.tile_data_length)?;
+        buf.write_u64::<LittleEndian>(self.num_addressed_tiles)?;
+        buf.write_u64::<LittleEndian>(self.num_tile_entries)?;
+        buf.write_u64::<LittleEndian>(self.num_tile_contents)?;
+        buf.write_u8(self.clustered)?;
+        buf.write_u8(self.internal_compression)?;
+        buf.write_u8(self.tile_compression)?;
+        buf.write_u8(self.tile_type)?;
+        buf.write_u8(self.min_zoom)?;
+        buf.write_u8(self.max_zoom)?;
+        buf.write_i32::<LittleEndian>((self.min_lon * 10_000_000.).round() as i32)?;
+        buf.write_i32::<LittleEndian>((self.min_lat * 10_000_000.).round() as i32)?;
+        buf.write_i32::<LittleEndian>((self.max_lon * 10_000_000.).round() as i32)?;
+        buf.write_i32::<LittleEndian>((self.max_lat * 10_000_000.).round() as i32)?;
+        buf.write_u8(self.center_zoom)?;
+        buf.write_i32::<LittleEndian>((self.center_lon * 10_000_000.).round() as i32)?;
+        buf.write_i32::<LittleEndian>((self.center_lat * 10_000_000.).round() as i32)?;
+        Ok(buf)
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TileEntry {
     pub tile_id: u64,
     pub offset: u64,
     pub length: u64,
     pub run_length: u64,
 }
+
+// Tile data as stored in the archive, along with its compression. The ETag is derived
+// from the ETag of the archive and the tile id when the archive has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawTile {
+    pub data: Bytes,
+    pub compression: Compression,
+    pub tile_type: TileType,
+    pub etag: Option<String>,
+}
+
+// A tile addressed by an archive. The offset is absolute within the archive, and tiles
+// of the same run share their offset and length.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveTile {
+    pub tile_id: u64,
+    pub z: u64,
+    pub x: u64,
+    pub y: u64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// Decoded root or leaf directory, shared as is between the cache and readers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Directory {
+    pub entries: Vec<TileEntry>,
+}
+
+impl Deref for Directory {
+    type Target = [TileEntry];
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl From<Vec<TileEntry>> for Directory {
+    fn from(entries: Vec<TileEntry>) -> Self {
+        Directory { entries }
+    }
+}
@@ -1,39 +1,73 @@
 use super::{
     cache::Cache,
-    compress::decompress,
+    compress::{decompress, detect_compression},
     fetcher::{Fetcher, FetcherError},
-    models::Headers,
+    models::{ArchiveTile, Directory, Headers, RawTile, TileEntry, TileType},
+    v2,
 };
 use crate::cache::CacheError;
 use crate::compress::Compression;
-use crate::helpers::{find_tile, get_entries, get_headers};
+use crate::helpers::{
+    find_tile, get_archive_range, get_headers, get_leaf_directory, log_cache_set, tile_id_to_zxy,
+    zxy_to_tile_id,
+};
+use bytes::Bytes;
 use std::num::TryFromIntError;
+use std::sync::Arc;
 use thiserror::Error;
 
-async fn fetch_metadata<T: Fetcher, C: Cache>(
+pub(crate) async fn fetch_metadata<T: Fetcher, C: Cache + ?Sized>(
     path: &str,
     headers: &Headers,
     client: &T,
     cache: Option<&C>,
-) -> anyhow::Result<Vec<u8>, PMTilesError> {
+) -> anyhow::Result<Bytes, PMTilesError> {
     let cache_key = format!("{}|metadata", path);
-    let (raw, _) = client
-        .get_data_range(
-            path,
-            headers.json_metadata_offset as usize,
-            headers.json_metadata_length as usize,
-        )
-        .await?;
-    let decompressed = decompress(&raw, Compression::from(headers.internal_compression))?
-        .into_iter()
-        .collect::<Vec<u8>>();
+    let raw = get_archive_range(
+        path,
+        headers,
+        headers.json_metadata_offset,
+        headers.json_metadata_length,
+        client,
+    )
+    .await?;
+    let mut decompressed = decompress(raw, Compression::from(headers.internal_compression))?;
+    if headers.spec_version == 2 {
+        decompressed = v2::normalize_metadata(&decompressed)?.into();
+    }
     if let Some(cache) = cache {
-        cache.set(&cache_key, &decompressed)?;
+        cache.set(&cache_key, decompressed.clone())?;
     }
     Ok(decompressed)
 }
 
-pub async fn get_metadata<T: Fetcher, C: Cache>(
+// Drop everything cached for an archive that has changed since it was cached
+fn invalidate_archive<C: Cache + ?Sized>(
+    path: &str,
+    cache: Option<&C>,
+) -> Result<(), PMTilesError> {
+    tracing::warn!("{} has changed, invalidating cached data", path);
+    if let Some(cache) = cache {
+        cache.invalidate(path)?;
+    }
+    Ok(())
+}
+
+pub async fn get_metadata<T: Fetcher, C: Cache + ?Sized>(
+    path: &str,
+    client: &T,
+    cache: Option<&C>,
+) -> anyhow::Result<(Headers, serde_json::Value), PMTilesError> {
+    match read_metadata(path, client, cache).await {
+        Err(PMTilesError::EtagMismatch()) => {
+            invalidate_archive(path, cache)?;
+            read_metadata(path, client, cache).await
+        }
+        res => res,
+    }
+}
+
+async fn read_metadata<T: Fetcher, C: Cache + ?Sized>(
     path: &str,
     client: &T,
     cache: Option<&C>,
@@ -49,11 +83,25 @@ pub async fn get_metadata<T: Fetcher, C: Cache>(
             None => fetch_metadata(path, &headers, client, cache).await?,
         }
     };
-    let json: serde_json::Value = serde_json::from_slice(&raw).map_err(|err| {
+    Ok((headers, parse_metadata(&raw)?))
+}
+
+// Get the tile type of an archive from its headers, which are read from the cache when
+// they are cached
+pub async fn get_tile_type<T: Fetcher, C: Cache + ?Sized>(
+    path: &str,
+    client: &T,
+    cache: Option<&C>,
+) -> Result<TileType, PMTilesError> {
+    let (headers, _) = get_headers(path, client, cache).await?;
+    Ok(TileType::from(headers.tile_type))
+}
+
+pub(crate) fn parse_metadata(raw: &[u8]) -> Result<serde_json::Value, PMTilesError> {
+    serde_json::from_slice(raw).map_err(|err| {
         tracing::error!("failed to deserialize json metadata: {}", err);
         PMTilesError::MetadataError("failed to deserialize tileset metadata".into())
-    })?;
-    Ok((headers, json))
+    })
 }
 
 #[cfg(test)]
@@ -112,56 +160,136 @@ async fn test_get_metadata() {
     );
 }
 
-pub async fn get_tile<T: Fetcher, C: Cache>(
+// ETag of a tile, derived from the ETag of its archive and the tile id
+pub fn tile_etag(archive_etag: &str, z: u64, x: u64, y: u64) -> Option<String> {
+    let tile_id = zxy_to_tile_id(z, x, y).ok()?;
+    let archive_etag = archive_etag.trim_start_matches("W/").trim_matches('"');
+    Some(format!("\"{}-{:x}\"", archive_etag, tile_id))
+}
+
+// Get the tile data as stored in the archive, without decompressing it. If the archive
+// changed since its headers were cached, the cache is invalidated and the read retried.
+pub async fn get_raw_tile<T: Fetcher, C: Cache + ?Sized>(
+    z: u64,
+    x: u64,
+    y: u64,
+    path: &str,
+    client: &T,
+    cache: Option<&C>,
+) -> anyhow::Result<RawTile, PMTilesError> {
+    match read_raw_tile(z, x, y, path, client, cache).await {
+        Err(PMTilesError::EtagMismatch()) => {
+            invalidate_archive(path, cache)?;
+            read_raw_tile(z, x, y, path, client, cache).await
+        }
+        res => res,
+    }
+}
+
+async fn read_raw_tile<T: Fetcher, C: Cache + ?Sized>(
     z: u64,
     x: u64,
     y: u64,
     path: &str,
     client: &T,
     cache: Option<&C>,
-) -> anyhow::Result<Vec<u8>, PMTilesError> {
-    let (headers, mut entries) = get_headers(path, client, cache).await?;
+) -> anyhow::Result<RawTile, PMTilesError> {
+    let (headers, directory) = get_headers(path, client, cache).await?;
     if z < headers.min_zoom as u64 || z > headers.max_zoom as u64 {
         return Err(PMTilesError::OutOfBoundsZ());
     }
 
-    let mut offset = headers.root_directory_offset.clone();
-    let mut length = headers.root_directory_length.clone();
-    let tile_compression = Compression::from(headers.tile_compression);
+    // Tiles are cached by the ETag of the archive, so tiles of a replaced archive are not used
+    let tile_id = zxy_to_tile_id(z, x, y)?;
+    let tile_key = format!(
+        "{}|tile|{:x}|{}",
+        path,
+        tile_id,
+        headers.etag.as_deref().unwrap_or_default()
+    );
+    let tile_data = match cache.and_then(|cache| cache.get_tile(&tile_key, z as u8)) {
+        Some(data) => {
+            tracing::debug!("cache hit for key {}", tile_key);
+            data
+        }
+        None => {
+            let tile_entry =
+                find_tile_entry(tile_id, path, &headers, directory, client, cache).await?;
+            let data = get_archive_range(
+                path,
+                &headers,
+                headers.tile_data_offset + tile_entry.offset,
+                tile_entry.length,
+                client,
+            )
+            .await?;
+            if let Some(cache) = cache {
+                log_cache_set(&tile_key, cache.set_tile(&tile_key, z as u8, data.clone()));
+            }
+            data
+        }
+    };
+    // Version 2 archives may not name their tile compression
+    let compression = match Compression::from(headers.tile_compression) {
+        Compression::Unknown => detect_compression(&tile_data),
+        compression => compression,
+    };
+    Ok(RawTile {
+        data: tile_data,
+        compression,
+        tile_type: TileType::from(headers.tile_type),
+        etag: headers
+            .etag
+            .as_deref()
+            .and_then(|etag| tile_etag(etag, z, x, y)),
+    })
+}
+
+// Walk the directories of an archive, starting from its root directory, to the entry of a tile
+async fn find_tile_entry<T: Fetcher, C: Cache + ?Sized>(
+    tile_id: u64,
+    path: &str,
+    headers: &Headers,
+    mut directory: Arc<Directory>,
+    client: &T,
+    cache: Option<&C>,
+) -> anyhow::Result<TileEntry, PMTilesError> {
+    if headers.spec_version == 2 {
+        return v2::find_tile(tile_id, path, headers, &directory, client, cache).await;
+    }
+    let (z, x, y) = tile_id_to_zxy(tile_id)?;
+    let mut offset = headers.root_directory_offset;
+    let mut length = headers.root_directory_length;
     for i in 0..4 {
         if i > 0 {
             // First iteration entry fetch is skipped because we already have it
             // from fetching headers. If further iterations are needed, fetch
             // new entry data from the nested offset.
-            let (tile_dir_data, _) = client
-                .get_data_range(path, offset as usize, length as usize)
-                .await?;
-            entries = get_entries(
-                &tile_dir_data,
-                Compression::from(headers.internal_compression),
-            )?
+            directory = get_leaf_directory(path, headers, offset, length, client, cache).await?;
         }
 
-        let tile_entry = find_tile(z, x, y, &entries)?;
-        if tile_entry.run_length > 0 {
-            let (tile_data, _) = client
-                .get_data_range(
-                    path,
-                    (headers.tile_data_offset + tile_entry.offset) as usize,
-                    tile_entry.length as usize,
-                )
-                .await?;
-            let decompressed = decompress(&tile_data, tile_compression)?
-                .into_iter()
-                .collect();
-            return Ok(decompressed);
+        let entry = find_tile(z, x, y, &directory)?;
+        if entry.run_length > 0 {
+            return Ok(entry.clone());
         }
-        offset = headers.leaf_directory_offset + tile_entry.offset;
-        length = tile_entry.length;
+        offset = headers.leaf_directory_offset + entry.offset;
+        length = entry.length;
     }
     Err(PMTilesError::NotFound(None))
 }
 
+pub async fn get_tile<T: Fetcher, C: Cache + ?Sized>(
+    z: u64,
+    x: u64,
+    y: u64,
+    path: &str,
+    client: &T,
+    cache: Option<&C>,
+) -> anyhow::Result<Bytes, PMTilesError> {
+    let tile = get_raw_tile(z, x, y, path, client, cache).await?;
+    Ok(decompress(tile.data, tile.compression)?)
+}
+
 #[cfg(test)]
 #[tokio::test]
 async fn test_get_headers() {
@@ -186,6 +314,58 @@ async fn test_get_headers() {
     assert_eq!(entries.len(), 28);
 }
 
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_headers_cached() {
+    use crate::cache::{CacheError, InMemoryCache};
+    use fxhash::FxHashMap;
+    use std::sync::{Arc, Mutex};
+
+    let client = crate::fetcher::LocalFetcher::new();
+    let path = std::path::Path::new("../../testdata/data/data.pmtiles")
+        .canonicalize()
+        .unwrap();
+    let path = path.to_str().unwrap();
+
+    // A cache hit returns the same decoded root directory
+    let cache = InMemoryCache::new();
+    let (_, root) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    let (headers, cached_root) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    assert!(Arc::ptr_eq(&root, &cached_root));
+    assert_eq!(headers.num_tile_entries, 28);
+    // The ETag of the archive is kept along with the cached headers
+    let etag = headers.etag.unwrap();
+    let tile = get_raw_tile(14, 9325, 4732, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    let tile_etag = tile.etag.unwrap();
+    assert!(tile_etag.starts_with(etag.trim_end_matches('"')));
+    assert_ne!(tile_etag, etag);
+
+    // Caches that only store bytes get the directory encoded as bytes
+    #[derive(Default)]
+    struct BytesCache(Mutex<FxHashMap<String, Bytes>>);
+    impl Cache for BytesCache {
+        fn get(&self, key: &str) -> Option<Bytes> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+        fn set(&self, key: &str, data: Bytes) -> Result<(), CacheError> {
+            self.0.lock().unwrap().insert(key.into(), data);
+            Ok(())
+        }
+        fn invalidate(&self, key: &str) -> Result<(), CacheError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+    let cache = BytesCache::default();
+    get_headers(path, &client, Some(&cache)).await.unwrap();
+    assert!(cache.get(&format!("{}|root", path)).is_some());
+    let (_, cached_root) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    assert_eq!(*root, *cached_root);
+}
+
+#[cfg(test)]
 #[tokio::test]
 async fn test_get_tile() {
     use crate::cache::InMemoryCache;
@@ -207,6 +387,252 @@ async fn test_get_tile() {
     assert_eq!(data.len(), 78408);
 }
 
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_tile_caches_leaf_directories() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::CountingFetcher;
+    use crate::writer::PMTilesWriter;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    for x in 0..130 {
+        for y in 0..130 {
+            writer
+                .add_tile(8, x, y, format!("{}/{}", x, y).as_bytes())
+                .unwrap();
+        }
+    }
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let headers = writer.finish(&mut file.as_file()).unwrap();
+    assert!(headers.leaf_directory_length > 0);
+
+    let client = CountingFetcher::new();
+    let cache = InMemoryCache::new();
+    let path = file.path().to_str().unwrap();
+    let tile = get_tile(8, 10, 10, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"10/10"[..]);
+    // Header block, leaf directory and tile data
+    assert_eq!(client.requests(), 3);
+
+    let tile = get_tile(8, 10, 11, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"10/11"[..]);
+    assert_eq!(client.requests(), 4);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_tile_from_tile_cache() {
+    use crate::cache::{InMemoryCache, TileCache};
+    use crate::fetcher::CountingFetcher;
+    use crate::writer::PMTilesWriter;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    writer.add_tile(0, 0, 0, b"low").unwrap();
+    writer.add_tile(8, 10, 10, b"high").unwrap();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    writer.finish(&mut file.as_file()).unwrap();
+
+    let client = CountingFetcher::new();
+    // Without a budget for other tiles, only the pinned low zoom tile is kept
+    let cache = InMemoryCache::new().with_tiles(TileCache::new(0, Some(6)));
+    let path = file.path().to_str().unwrap();
+    for (z, x, y, data) in [(0, 0, 0, &b"low"[..]), (8, 10, 10, &b"high"[..])] {
+        let tile = get_tile(z, x, y, path, &client, Some(&cache))
+            .await
+            .unwrap();
+        assert_eq!(tile, data);
+    }
+    // Header block and the data of both tiles
+    assert_eq!(client.requests(), 3);
+
+    let tile = get_tile(0, 0, 0, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"low"[..]);
+    assert_eq!(client.requests(), 3);
+    let tile = get_tile(8, 10, 10, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"high"[..]);
+    assert_eq!(client.requests(), 4);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_tile_after_archive_changed() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::LocalFetcher;
+    use crate::writer::PMTilesWriter;
+
+    fn write_archive(path: &std::path::Path, contents: &str) {
+        let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+        writer.add_tile(1, 1, 1, contents.as_bytes()).unwrap();
+        writer
+            .finish(&mut std::fs::File::create(path).unwrap())
+            .unwrap();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("changing.pmtiles");
+    let path = file.to_str().unwrap();
+    let client = LocalFetcher::new();
+    let cache = InMemoryCache::new();
+
+    write_archive(&file, "old");
+    let tile = get_tile(1, 1, 1, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"old"[..]);
+    // Headers read from the cache carry the ETag of the archive
+    let (headers, _) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    let old_etag = headers.etag.unwrap();
+
+    write_archive(&file, "replaced");
+    let tile = get_tile(1, 1, 1, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"replaced"[..]);
+    let (headers, _) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    assert_ne!(headers.etag.unwrap(), old_etag);
+}
+
+// Walks every tile addressed by an archive in tile id order, reading leaf directories
+// as they are reached and expanding run-length entries into their tiles
+pub struct TileIterator<'a, T: Fetcher, C: Cache + ?Sized> {
+    path: &'a str,
+    client: &'a T,
+    cache: Option<&'a C>,
+    headers: Headers,
+    // Directories being walked along with the index of their next entry
+    directories: Vec<(Arc<Directory>, usize)>,
+    // Entry being expanded along with the number of its tiles yielded so far
+    run: Option<(TileEntry, u64)>,
+}
+
+pub async fn iterate_tiles<'a, T: Fetcher, C: Cache + ?Sized>(
+    path: &'a str,
+    client: &'a T,
+    cache: Option<&'a C>,
+) -> anyhow::Result<TileIterator<'a, T, C>, PMTilesError> {
+    let (headers, root) = get_headers(path, client, cache).await?;
+    Ok(TileIterator {
+        path,
+        client,
+        cache,
+        headers,
+        directories: vec![(root, 0)],
+        run: None,
+    })
+}
+
+impl<T: Fetcher, C: Cache + ?Sized> TileIterator<'_, T, C> {
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    // Number of directories from the root down to the one being walked
+    pub fn depth(&self) -> usize {
+        self.directories.len()
+    }
+
+    pub async fn next(&mut self) -> anyhow::Result<Option<ArchiveTile>, PMTilesError> {
+        loop {
+            if let Some((entry, yielded)) = &mut self.run {
+                if *yielded < entry.run_length {
+                    let tile_id = entry.tile_id + *yielded;
+                    *yielded += 1;
+                    let (z, x, y) = tile_id_to_zxy(tile_id)?;
+                    return Ok(Some(ArchiveTile {
+                        tile_id,
+                        z,
+                        x,
+                        y,
+                        offset: self.headers.tile_data_offset + entry.offset,
+                        length: entry.length,
+                    }));
+                }
+                self.run = None;
+            }
+            let Some((directory, index)) = self.directories.last_mut() else {
+                return Ok(None);
+            };
+            let Some(entry) = directory.get(*index).cloned() else {
+                self.directories.pop();
+                continue;
+            };
+            *index += 1;
+            if entry.run_length > 0 {
+                self.run = Some((entry, 0));
+                continue;
+            }
+            let leaf = get_leaf_directory(
+                self.path,
+                &self.headers,
+                self.headers.leaf_directory_offset + entry.offset,
+                entry.length,
+                self.client,
+                self.cache,
+            )
+            .await?;
+            self.directories.push((leaf, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_iterate_tiles() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::LocalFetcher;
+    use crate::writer::PMTilesWriter;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    for x in 0..150 {
+        for y in 0..150 {
+            // The last columns share their contents to produce run-length entries
+            let contents = if x >= 140 {
+                "shared".to_string()
+            } else {
+                format!("{}/{}", x, y)
+            };
+            writer.add_tile(8, x, y, contents.as_bytes()).unwrap();
+        }
+    }
+    writer.add_tile(0, 0, 0, b"world").unwrap();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let headers = writer.finish(&mut file.as_file()).unwrap();
+    assert!(headers.leaf_directory_length > 0);
+    assert!(headers.num_tile_entries < headers.num_addressed_tiles);
+
+    let client = LocalFetcher::new();
+    let path = file.path().to_str().unwrap();
+    let mut tiles = iterate_tiles(path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    let data = std::fs::read(path).unwrap();
+    let mut count = 0;
+    let mut last_tile_id = None;
+    while let Some(tile) = tiles.next().await.unwrap() {
+        assert!(last_tile_id < Some(tile.tile_id));
+        last_tile_id = Some(tile.tile_id);
+        let contents = &data[tile.offset as usize..(tile.offset + tile.length) as usize];
+        let expected = match (tile.z, tile.x) {
+            (0, _) => "world".to_string(),
+            (_, x) if x >= 140 => "shared".to_string(),
+            (_, x) => format!("{}/{}", x, tile.y),
+        };
+        assert_eq!(contents, expected.as_bytes());
+        count += 1;
+    }
+    assert_eq!(count, 150 * 150 + 1);
+    assert_eq!(tiles.headers().num_addressed_tiles, count);
+    assert!(tiles.next().await.unwrap().is_none());
+}
+
 #[derive(Error, Debug)]
 pub enum PMTilesError {
     #[error("tile out of bounds error")]
@@ -227,6 +653,8 @@ pub enum PMTilesError {
     MetadataError(String),
     #[error(transparent)]
     CacheError(#[from] CacheError),
+    #[error("archive changed while reading it")]
+    EtagMismatch(),
 }
 
 impl From<FetcherError> for PMTilesError {
@@ -234,6 +662,8 @@ impl From<FetcherError> for PMTilesError {
         match err {
             FetcherError::NotFound() => PMTilesError::NotFound(None),
             FetcherError::S3Error(err) => PMTilesError::BadRequest(err.to_string()),
+            FetcherError::HttpError(err) => PMTilesError::BadRequest(err.to_string()),
+            FetcherError::EtagMismatch() => PMTilesError::EtagMismatch(),
             FetcherError::Other(err) => PMTilesError::Internal(err.to_string()),
         }
     }
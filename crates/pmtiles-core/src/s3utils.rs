@@ -1,17 +1,16 @@
+#[cfg(feature = "s3")]
 use super::fetcher::FetcherError;
 #[cfg(feature = "s3")]
 use aws_sdk_s3 as s3;
+#[cfg(feature = "s3")]
+use bytes::Bytes;
 
 // Check whether path is S3 path, i.e. starts with s3-protocol specifier
 pub fn is_s3_path(path: &str) -> bool {
-    if path.starts_with(&"s3://") {
-        true
-    } else {
-        false
-    }
+    path.starts_with("s3://")
 }
 
-pub fn bucket_and_key_from_path<'a>(path: &'a str) -> anyhow::Result<(&'a str, String)> {
+pub fn bucket_and_key_from_path(path: &str) -> anyhow::Result<(&str, String)> {
     let parts: Vec<&str> = path.trim_start_matches("s3://").split("/").collect();
     if parts.len() < 2 {
         anyhow::bail!("invalid path")
@@ -25,7 +24,8 @@ pub async fn get_object_range(
     client: &s3::Client,
     offset: usize,
     length: usize,
-) -> anyhow::Result<(Vec<u8>, Option<String>), FetcherError> {
+    if_match: Option<&str>,
+) -> anyhow::Result<(Bytes, Option<String>), FetcherError> {
     let (bucket, key) = bucket_and_key_from_path(path)?;
     tracing::debug!(
         "get_object_range bucket={}, key={}, offset={}, length={}",
@@ -34,11 +34,15 @@ pub async fn get_object_range(
         offset,
         length
     );
+    if length == 0 {
+        return Ok((Bytes::new(), None));
+    }
     let res = client
         .get_object()
         .bucket(bucket)
         .key(key)
-        .range(format!("bytes={}-{}", offset, offset + length))
+        .range(format!("bytes={}-{}", offset, offset + length - 1))
+        .set_if_match(if_match.map(Into::into))
         .send()
         .await
         .map_err(Into::<s3::Error>::into)?;
@@ -48,19 +52,49 @@ pub async fn get_object_range(
         .collect()
         .await
         .map_err(|_| anyhow::anyhow!("unable to get data"))?;
-    Ok((data.to_vec(), etag))
+    Ok((data.into_bytes(), etag))
 }
 
 #[cfg(feature = "s3")]
 pub async fn get_object(
     path: &str,
     client: &s3::Client,
-) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+) -> anyhow::Result<(Bytes, Option<String>)> {
     let (bucket, key) = bucket_and_key_from_path(path)?;
     tracing::debug!("get_object bucket={}, key={}", bucket, key);
 
     let res = client.get_object().bucket(bucket).key(key).send().await?;
     let etag = res.e_tag;
     let data = res.body.collect().await?;
-    Ok((data.to_vec(), etag))
+    Ok((data.into_bytes(), etag))
+}
+
+#[cfg(feature = "s3")]
+pub async fn list_objects(path: &str, client: &s3::Client) -> anyhow::Result<Vec<String>> {
+    let (bucket, key) = bucket_and_key_from_path(path)?;
+    let prefix = format!("{}/", key.trim_end_matches('/'));
+    tracing::debug!("list_objects bucket={}, prefix={}", bucket, prefix);
+
+    let mut paths = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let res = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(&prefix)
+            .delimiter("/")
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+        for object in res.contents() {
+            if let Some(key) = object.key() {
+                paths.push(format!("s3://{}/{}", bucket, key));
+            }
+        }
+        match res.next_continuation_token {
+            Some(token) => continuation_token = Some(token),
+            None => break,
+        }
+    }
+    Ok(paths)
 }
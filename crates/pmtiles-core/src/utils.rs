@@ -36,7 +36,7 @@ pub fn read_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
     *pos += 1;
     let mut val = b & 0x7f;
     if b < 0x80 {
-        return Ok(val as u64);
+        return Ok(val);
     }
     for i in 1..4 {
         if *pos >= data.len() {
@@ -46,14 +46,25 @@ pub fn read_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
         *pos += 1;
         val |= (b & 0x7f) << (7 * (i));
         if b < 0x80 {
-            return Ok(val as u64);
+            return Ok(val);
         }
     }
+    if *pos >= data.len() {
+        return Err(anyhow::anyhow!("out-of-bounds data access"));
+    }
     b = data[*pos] as u64;
     val |= (b & 0x0f) << 28;
     read_varint_remainder(data, pos, val)
 }
 
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
 fn to_num(low: u64, high: u64) -> u64 {
     high << 32 | low
 }
@@ -65,6 +76,9 @@ fn read_varint_remainder(data: &[u8], pos: &mut usize, val: u64) -> anyhow::Resu
     if b < 0x80 {
         return Ok(to_num(val, high));
     }
+    if *pos >= data.len() {
+        return Err(anyhow::anyhow!("out-of-bounds data access"));
+    }
     b = data[*pos] as u64;
     *pos += 1;
     high |= (b & 0x7f) << 3;
@@ -78,7 +92,7 @@ fn read_varint_remainder(data: &[u8], pos: &mut usize, val: u64) -> anyhow::Resu
         }
         b = data[*pos] as u64;
         *pos += 1;
-        high |= ((b & 0x7f) as u64) << (3 + 7 * i);
+        high |= (b & 0x7f) << (3 + 7 * i);
     }
     if b < 0x80 {
         return Ok(to_num(val, high));
@@ -86,15 +100,41 @@ fn read_varint_remainder(data: &[u8], pos: &mut usize, val: u64) -> anyhow::Resu
     Err(anyhow::anyhow!("expected varint not more than 10 bytes"))
 }
 
+// Longitude and latitude bounds of a tile as [min_lon, min_lat, max_lon, max_lat]
+pub fn tile_bounds(z: u8, x: u64, y: u64) -> [f64; 4] {
+    let n = 2_f64.powi(z as i32);
+    let lon = |x: f64| x / n * 360. - 180.;
+    let lat = |y: f64| {
+        (std::f64::consts::PI * (1. - 2. * y / n))
+            .sinh()
+            .atan()
+            .to_degrees()
+    };
+    [
+        lon(x as f64),
+        lat((y + 1) as f64),
+        lon((x + 1) as f64),
+        lat(y as f64),
+    ]
+}
+
+// Tile containing a longitude and latitude, clamped to the extent of the web mercator grid
+pub fn lonlat_to_tile(z: u8, lon: f64, lat: f64) -> (u64, u64) {
+    let n = 2_f64.powi(z as i32);
+    let lat = lat.clamp(-85.05112878, 85.05112878).to_radians();
+    let x = (lon + 180.) / 360. * n;
+    let y = (1. - lat.tan().asinh() / std::f64::consts::PI) / 2. * n;
+    let max = n - 1.;
+    (x.clamp(0., max) as u64, y.clamp(0., max) as u64)
+}
+
 pub fn rotate(n: i64, x: &mut i64, y: &mut i64, rx: i64, ry: i64) {
     if ry == 0 {
         if rx == 1 {
             *x = n - 1 - *x;
             *y = n - 1 - *y;
         }
-        let tmp_x = x.clone();
-        *x = *y;
-        *y = tmp_x;
+        std::mem::swap(&mut *x, &mut *y);
     }
 }
 
@@ -151,3 +191,19 @@ fn test_varint() {
     let v = read_varint(&data, &mut pos).unwrap();
     assert_eq!(v, 3742845);
 }
+
+#[test]
+fn test_write_varint() {
+    let values = [0, 1, 127, 128, 624485, 3742845, 31397288418429, u64::MAX];
+    let mut data = Vec::new();
+    for value in values {
+        write_varint(&mut data, value);
+    }
+    assert_eq!(&data[..6], &[0, 1, 127, 0x80, 0x01, 0xe5]);
+
+    let mut pos = 0;
+    for value in values {
+        assert_eq!(read_varint(&data, &mut pos).unwrap(), value);
+    }
+    assert_eq!(pos, data.len());
+}
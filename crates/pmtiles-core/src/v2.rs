@@ -0,0 +1,427 @@
+use crate::cache::{Cache, InMemoryCache};
+use crate::compress::Compression;
+use crate::extract::write_tiles;
+use crate::fetcher::Fetcher;
+use crate::helpers::{get_leaf_directory, tile_id_to_zxy, zxy_to_tile_id};
+use crate::models::{Directory, Headers, TileEntry, TileType};
+use crate::pmtiles::{get_metadata, iterate_tiles, PMTilesError};
+use crate::writer::PMTilesWriter;
+use byteorder::{LittleEndian, ReadBytesExt};
+use bytes::Bytes;
+use serde_json::{Map, Value};
+use std::io::{Cursor, Write};
+
+// PMTiles version 2 archives start with a 10 byte header followed by the uncompressed
+// JSON metadata and root directory. Directories are lists of 17 byte entries addressing
+// tiles by z/x/y, and leaf directories are all pointed at from the root directory by the
+// tile at a single leaf zoom level containing their tiles.
+
+const V2_HEADER_SIZE_BYTES: usize = 10;
+const V2_ENTRY_SIZE_BYTES: usize = 17;
+// Flag of the zoom level marking entries that point at leaf directories
+const LEAF_FLAG: u8 = 0b1000_0000;
+
+// Decode a version 2 directory. Entries are sorted by tile id, with leaf directories
+// as entries without a run length.
+pub(crate) fn decode_directory(data: &[u8]) -> anyhow::Result<Directory> {
+    if !data
+        .chunks_exact(V2_ENTRY_SIZE_BYTES)
+        .remainder()
+        .is_empty()
+    {
+        anyhow::bail!("version 2 directory length is not a multiple of the entry size");
+    }
+    let mut rdr = Cursor::new(data);
+    let mut entries = Vec::with_capacity(data.len() / V2_ENTRY_SIZE_BYTES);
+    for _ in 0..data.len() / V2_ENTRY_SIZE_BYTES {
+        let z = rdr.read_u8()?;
+        let x = rdr.read_u24::<LittleEndian>()? as u64;
+        let y = rdr.read_u24::<LittleEndian>()? as u64;
+        let offset = rdr.read_u48::<LittleEndian>()?;
+        let length = rdr.read_u32::<LittleEndian>()? as u64;
+        entries.push(TileEntry {
+            tile_id: zxy_to_tile_id((z & !LEAF_FLAG) as u64, x, y)?,
+            offset,
+            length,
+            run_length: if z & LEAF_FLAG == 0 { 1 } else { 0 },
+        });
+    }
+    entries.sort_unstable_by_key(|entry| (entry.tile_id, entry.run_length));
+    Ok(Directory::from(entries))
+}
+
+fn find_entry(directory: &Directory, tile_id: u64, leaf: bool) -> Option<&TileEntry> {
+    let start = directory.partition_point(|entry| entry.tile_id < tile_id);
+    directory[start..]
+        .iter()
+        .take_while(|entry| entry.tile_id == tile_id)
+        .find(|entry| (entry.run_length == 0) == leaf)
+}
+
+// Find the entry of a tile from the root directory or the leaf directory containing it
+pub(crate) async fn find_tile<T: Fetcher, C: Cache + ?Sized>(
+    tile_id: u64,
+    path: &str,
+    headers: &Headers,
+    root: &Directory,
+    client: &T,
+    cache: Option<&C>,
+) -> Result<TileEntry, PMTilesError> {
+    if let Some(entry) = find_entry(root, tile_id, false) {
+        return Ok(entry.clone());
+    }
+    let (z, x, y) = tile_id_to_zxy(tile_id)?;
+    let leaf_zoom = match root.iter().find(|entry| entry.run_length == 0) {
+        Some(entry) => tile_id_to_zxy(entry.tile_id)?.0,
+        None => return Err(PMTilesError::NotFound(None)),
+    };
+    if z < leaf_zoom {
+        return Err(PMTilesError::NotFound(None));
+    }
+    let shift = z - leaf_zoom;
+    let leaf_id = zxy_to_tile_id(leaf_zoom, x >> shift, y >> shift)?;
+    let leaf_entry = find_entry(root, leaf_id, true).ok_or(PMTilesError::NotFound(None))?;
+    let leaf = get_leaf_directory(
+        path,
+        headers,
+        leaf_entry.offset,
+        leaf_entry.length,
+        client,
+        cache,
+    )
+    .await?;
+    find_entry(&leaf, tile_id, false)
+        .cloned()
+        .ok_or(PMTilesError::NotFound(None))
+}
+
+// Numbers of a metadata value given either as a comma separated string or as JSON numbers
+fn metadata_numbers(metadata: &Value, key: &str) -> Option<Vec<f64>> {
+    match metadata.get(key)? {
+        Value::String(value) => value
+            .split(',')
+            .map(|part| part.trim().parse::<f64>().ok())
+            .collect(),
+        Value::Number(value) => Some(vec![value.as_f64()?]),
+        Value::Array(values) => values.iter().map(Value::as_f64).collect(),
+        _ => None,
+    }
+}
+
+// Merge the keys of the nested `json` string, which holds e.g. the vector layers
+pub(crate) fn normalize_metadata(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut metadata: Map<String, Value> = serde_json::from_slice(data)?;
+    if let Some(Value::String(nested)) = metadata.remove("json") {
+        match serde_json::from_str(&nested)? {
+            Value::Object(fields) => metadata.extend(fields),
+            _ => anyhow::bail!("metadata json is not an object"),
+        }
+    }
+    Ok(serde_json::to_vec(&metadata)?)
+}
+
+// Read the header block of a version 2 archive, given at least its first bytes, and
+// describe it with a version 3 header. Offsets of version 2 archives are absolute, so the
+// tile data and leaf directory sections are taken to start from the beginning.
+pub(crate) async fn read_header_block<T: Fetcher>(
+    path: &str,
+    data: Bytes,
+    etag: Option<&str>,
+    client: &T,
+) -> Result<(Headers, Directory), PMTilesError> {
+    let mut rdr = Cursor::new(&data[..]);
+    rdr.set_position(4);
+    let metadata_length = rdr
+        .read_u32::<LittleEndian>()
+        .map_err(anyhow::Error::from)? as u64;
+    let root_entries = rdr
+        .read_u16::<LittleEndian>()
+        .map_err(anyhow::Error::from)? as u64;
+    let root_offset = V2_HEADER_SIZE_BYTES as u64 + metadata_length;
+    let root_length = root_entries * V2_ENTRY_SIZE_BYTES as u64;
+    let block_length = (root_offset + root_length) as usize;
+    let data = if data.len() >= block_length {
+        data
+    } else {
+        let (data, _) = match etag {
+            Some(etag) => {
+                client
+                    .get_data_range_if_match(path, 0, block_length, etag)
+                    .await?
+            }
+            None => client.get_data_range(path, 0, block_length).await?,
+        };
+        data
+    };
+    let metadata_data = data
+        .get(V2_HEADER_SIZE_BYTES..root_offset as usize)
+        .ok_or_else(|| anyhow::anyhow!("version 2 metadata is truncated"))?;
+    let metadata: Value = serde_json::from_slice(metadata_data).map_err(anyhow::Error::from)?;
+    let root_data = data
+        .get(root_offset as usize..block_length)
+        .ok_or_else(|| anyhow::anyhow!("version 2 root directory is truncated"))?;
+    let root = decode_directory(root_data)?;
+
+    let format = metadata.get("format").and_then(Value::as_str);
+    let tile_type = format.map(TileType::from_format);
+    let tile_compression = match metadata.get("compression").and_then(Value::as_str) {
+        Some("gzip") => Compression::Gzip,
+        Some("br") | Some("brotli") => Compression::Brotli,
+        Some("zstd") => Compression::Zstd,
+        Some("none") => Compression::None,
+        _ => Compression::Unknown,
+    };
+    let zoom = |key| {
+        metadata_numbers(&metadata, key)
+            .and_then(|values| values.first().copied())
+            .map(|zoom| zoom as u8)
+    };
+    let bounds = metadata_numbers(&metadata, "bounds")
+        .filter(|bounds| bounds.len() == 4)
+        .unwrap_or(vec![-180., -85., 180., 85.]);
+    let center = metadata_numbers(&metadata, "center").filter(|center| center.len() == 3);
+    let min_zoom = zoom("minzoom").unwrap_or(0);
+    let max_zoom = zoom("maxzoom").unwrap_or(26);
+    let headers = Headers {
+        spec_version: 2,
+        root_directory_offset: root_offset,
+        root_directory_length: root_length,
+        json_metadata_offset: V2_HEADER_SIZE_BYTES as u64,
+        json_metadata_length: metadata_length,
+        leaf_directory_offset: 0,
+        leaf_directory_length: 0,
+        tile_data_offset: 0,
+        tile_data_length: 0,
+        num_addressed_tiles: 0,
+        num_tile_entries: 0,
+        num_tile_contents: 0,
+        clustered: 0,
+        internal_compression: Compression::None.into(),
+        tile_compression: tile_compression.into(),
+        tile_type: tile_type.unwrap_or(TileType::Unknown).into(),
+        min_zoom,
+        max_zoom,
+        min_lon: bounds[0],
+        min_lat: bounds[1],
+        max_lon: bounds[2],
+        max_lat: bounds[3],
+        center_zoom: center.as_ref().map_or(min_zoom, |center| center[2] as u8),
+        center_lon: center
+            .as_ref()
+            .map_or((bounds[0] + bounds[2]) / 2., |center| center[0]),
+        center_lat: center
+            .as_ref()
+            .map_or((bounds[1] + bounds[3]) / 2., |center| center[1]),
+        etag: None,
+    };
+    Ok((headers, root))
+}
+
+// Convert a version 2 archive into a version 3 archive
+pub async fn upgrade<T: Fetcher, W: Write>(
+    path: &str,
+    client: &T,
+    out: &mut W,
+) -> Result<Headers, PMTilesError> {
+    let (headers, metadata) = get_metadata(path, client, None as Option<&InMemoryCache>).await?;
+    if headers.spec_version != 2 {
+        return Err(PMTilesError::BadRequest(format!(
+            "{} is not a version 2 archive",
+            path
+        )));
+    }
+    let mut tiles = Vec::new();
+    let mut archive_tiles = iterate_tiles(path, client, None as Option<&InMemoryCache>).await?;
+    while let Some(tile) = archive_tiles.next().await? {
+        tiles.push((tile.offset, tile.length, tile.tile_id));
+    }
+    tiles.sort_unstable();
+
+    let mut tile_compression = Compression::from(headers.tile_compression);
+    if tile_compression == Compression::Unknown {
+        // Older archives do not always name their compression, so detect it from a tile
+        if let Some((offset, length, _)) = tiles.first() {
+            let (data, _) = client
+                .get_data_range(path, *offset as usize, (*length).min(4) as usize)
+                .await?;
+            tile_compression = crate::compress::detect_compression(&data);
+        }
+    }
+    let mut writer = PMTilesWriter::new(TileType::from(headers.tile_type), tile_compression)?;
+    writer.set_metadata(metadata);
+    writer.set_bounds(
+        headers.min_lon,
+        headers.min_lat,
+        headers.max_lon,
+        headers.max_lat,
+    );
+    writer.set_center(headers.center_lon, headers.center_lat, headers.center_zoom);
+    write_tiles(path, &headers, &tiles, client, &mut writer).await?;
+    Ok(writer.finish(out)?)
+}
+
+// Write a version 2 archive with the given tiles, pointing at leaf directories from the
+// root directory for tiles at or below the leaf zoom level
+#[cfg(test)]
+pub(crate) fn write_v2_archive(
+    tiles: &[(u8, u64, u64, &[u8])],
+    metadata: &Value,
+    leaf_zoom: Option<u8>,
+) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    use std::collections::BTreeMap;
+
+    fn write_entry(buf: &mut Vec<u8>, z: u8, x: u64, y: u64, offset: u64, length: u64) {
+        buf.write_u8(z).unwrap();
+        buf.write_u24::<LittleEndian>(x as u32).unwrap();
+        buf.write_u24::<LittleEndian>(y as u32).unwrap();
+        buf.write_u48::<LittleEndian>(offset).unwrap();
+        buf.write_u32::<LittleEndian>(length as u32).unwrap();
+    }
+
+    let metadata = serde_json::to_vec(metadata).unwrap();
+    let mut root_tiles = Vec::new();
+    let mut leaves: BTreeMap<(u64, u64), Vec<_>> = BTreeMap::new();
+    for (z, x, y, data) in tiles {
+        match leaf_zoom {
+            Some(leaf_zoom) if *z >= leaf_zoom => {
+                let shift = z - leaf_zoom;
+                leaves
+                    .entry((x >> shift, y >> shift))
+                    .or_default()
+                    .push((*z, *x, *y, *data))
+            }
+            _ => root_tiles.push((*z, *x, *y, *data)),
+        }
+    }
+    let root_entries = root_tiles.len() + leaves.len();
+    let root_length = root_entries * V2_ENTRY_SIZE_BYTES;
+    let mut offset = (V2_HEADER_SIZE_BYTES + metadata.len() + root_length) as u64;
+
+    let mut root = Vec::new();
+    let mut body = Vec::new();
+    for (z, x, y, data) in root_tiles {
+        write_entry(&mut root, z, x, y, offset, data.len() as u64);
+        body.extend_from_slice(data);
+        offset += data.len() as u64;
+    }
+    for ((leaf_x, leaf_y), leaf_tiles) in leaves {
+        let leaf_length = (leaf_tiles.len() * V2_ENTRY_SIZE_BYTES) as u64;
+        write_entry(
+            &mut root,
+            leaf_zoom.unwrap() | LEAF_FLAG,
+            leaf_x,
+            leaf_y,
+            offset,
+            leaf_length,
+        );
+        let mut tile_offset = offset + leaf_length;
+        let mut leaf = Vec::new();
+        let mut data = Vec::new();
+        for (z, x, y, tile) in leaf_tiles {
+            write_entry(&mut leaf, z, x, y, tile_offset, tile.len() as u64);
+            data.extend_from_slice(tile);
+            tile_offset += tile.len() as u64;
+        }
+        body.extend(leaf);
+        body.extend(data);
+        offset = tile_offset;
+    }
+
+    let mut archive = vec![0x50, 0x4d, 0x02, 0x00];
+    archive
+        .write_u32::<LittleEndian>(metadata.len() as u32)
+        .unwrap();
+    archive
+        .write_u16::<LittleEndian>(root_entries as u16)
+        .unwrap();
+    archive.extend(metadata);
+    archive.extend(root);
+    archive.extend(body);
+    archive
+}
+
+#[tokio::test]
+async fn test_v2_archive() {
+    use crate::fetcher::LocalFetcher;
+    use crate::helpers::get_headers;
+    use crate::pmtiles::{get_raw_tile, get_tile};
+
+    let mut tiles = vec![(0, 0, 0, b"world".as_slice())];
+    let mut contents = Vec::new();
+    for x in 0..4 {
+        for y in 0..4 {
+            contents.push((x, y, format!("2/{}/{}", x, y)));
+        }
+    }
+    for (x, y, data) in &contents {
+        tiles.push((2, *x, *y, data.as_bytes()));
+    }
+    let metadata = serde_json::json!({
+        "name": "v2",
+        "format": "pbf",
+        "minzoom": "0",
+        "maxzoom": "2",
+        "bounds": "-180,-85,180,85",
+        "center": "0,0,1",
+        "json": "{\"vector_layers\":[{\"id\":\"layer\"}]}"
+    });
+    let archive = write_v2_archive(&tiles, &metadata, Some(1));
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), archive).unwrap();
+    let path = file.path().to_str().unwrap();
+    let client = LocalFetcher::new();
+    let cache = InMemoryCache::new();
+
+    let (headers, metadata) = get_metadata(path, &client, Some(&cache)).await.unwrap();
+    assert_eq!(headers.spec_version, 2);
+    assert_eq!(TileType::from(headers.tile_type), TileType::Mvt);
+    assert_eq!((headers.min_zoom, headers.max_zoom), (0, 2));
+    assert_eq!(metadata["vector_layers"][0]["id"], "layer");
+    assert!(metadata.get("json").is_none());
+
+    let tile = get_tile(0, 0, 0, path, &client, Some(&cache))
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"world"[..]);
+    // Tiles of leaf directories, read again from the cache
+    for _ in 0..2 {
+        let tile = get_tile(2, 3, 1, path, &client, Some(&cache))
+            .await
+            .unwrap();
+        assert_eq!(tile, &b"2/3/1"[..]);
+    }
+    let res = get_raw_tile(1, 0, 0, path, &client, Some(&cache)).await;
+    assert!(matches!(res, Err(PMTilesError::NotFound(_))));
+    // Cached headers keep the version of the archive, which the version 3 layout rejects
+    let (cached_headers, _) = get_headers(path, &client, Some(&cache)).await.unwrap();
+    assert_eq!(cached_headers.spec_version, 2);
+    assert!(Headers::from_bytes(&headers.to_bytes().unwrap()).is_err());
+
+    let mut upgraded = tempfile::NamedTempFile::new().unwrap();
+    let upgraded_headers = upgrade(path, &client, &mut upgraded).await.unwrap();
+    assert_eq!(upgraded_headers.spec_version, 3);
+    assert_eq!(upgraded_headers.num_addressed_tiles, 17);
+    let upgraded_path = upgraded.path().to_str().unwrap();
+    let (_, upgraded_metadata) =
+        get_metadata(upgraded_path, &client, None as Option<&InMemoryCache>)
+            .await
+            .unwrap();
+    assert_eq!(upgraded_metadata, metadata);
+    for (x, y, data) in &contents {
+        let tile = get_tile(
+            2,
+            *x,
+            *y,
+            upgraded_path,
+            &client,
+            None as Option<&InMemoryCache>,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tile, data.as_bytes());
+    }
+    assert!(upgrade(upgraded_path, &client, &mut std::io::sink())
+        .await
+        .is_err());
+}
@@ -0,0 +1,445 @@
+use crate::compress::{decompress, Compression};
+use crate::fetcher::Fetcher;
+use crate::helpers::{get_entries, tile_id_to_zxy};
+use crate::models::{Headers, TileEntry, HEADER_SIZE_BYTES};
+use bytes::Bytes;
+use fxhash::FxHashSet as HashSet;
+use serde::Serialize;
+
+// Only the first errors are listed to keep reports of badly broken archives readable
+const MAX_REPORTED_ERRORS: usize = 100;
+
+// Levels of leaf directories below the root directory, as allowed by readers
+const MAX_LEAF_DEPTH: usize = 3;
+
+// Result of checking the structure of an archive. The counts are gathered from the
+// directories that could be read.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub path: String,
+    pub valid: bool,
+    pub directories: u64,
+    pub tile_entries: u64,
+    pub addressed_tiles: u64,
+    pub tile_contents: u64,
+    pub error_count: usize,
+    pub errors: Vec<String>,
+}
+
+impl VerifyReport {
+    fn error(&mut self, message: String) {
+        tracing::debug!("{}: {}", self.path, message);
+        self.error_count += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(message);
+        }
+    }
+}
+
+struct Verifier<'a, T: Fetcher> {
+    path: &'a str,
+    client: &'a T,
+    headers: Headers,
+    report: VerifyReport,
+    // Tile id following the last tile entry, as entries must be sorted across all directories
+    next_tile_id: u64,
+    contents: HashSet<u64>,
+    // End of the furthest tile data seen, used to check that clustered data is ordered
+    tile_data_end: u64,
+}
+
+impl<T: Fetcher> Verifier<'_, T> {
+    async fn read_range(&self, offset: u64, length: u64) -> anyhow::Result<Bytes> {
+        let (data, _) = self
+            .client
+            .get_data_range(self.path, offset as usize, length as usize)
+            .await?;
+        if (data.len() as u64) < length {
+            anyhow::bail!("expected {} bytes but read {}", length, data.len());
+        }
+        Ok(data)
+    }
+
+    async fn check_sections(&mut self) {
+        let headers = &self.headers;
+        let sections = [
+            (
+                "root directory",
+                headers.root_directory_offset,
+                headers.root_directory_length,
+            ),
+            (
+                "metadata",
+                headers.json_metadata_offset,
+                headers.json_metadata_length,
+            ),
+            (
+                "leaf directories",
+                headers.leaf_directory_offset,
+                headers.leaf_directory_length,
+            ),
+            (
+                "tile data",
+                headers.tile_data_offset,
+                headers.tile_data_length,
+            ),
+        ];
+        let mut end = HEADER_SIZE_BYTES as u64;
+        let mut errors = Vec::new();
+        for (name, offset, length) in sections {
+            match offset.checked_add(length) {
+                Some(section_end) if offset >= HEADER_SIZE_BYTES as u64 => {
+                    end = end.max(section_end)
+                }
+                _ => errors.push(format!(
+                    "{} section at offset {} with length {} is out of bounds",
+                    name, offset, length
+                )),
+            }
+        }
+        if headers
+            .root_directory_offset
+            .saturating_add(headers.root_directory_length)
+            > 16384
+        {
+            errors.push("root directory does not fit in the first 16384 bytes".into());
+        }
+        for error in errors {
+            self.report.error(error);
+        }
+        // A truncated archive ends before its last section does
+        if let Err(err) = self.read_range(end - 1, 1).await {
+            self.report.error(format!(
+                "archive is truncated, expected {} bytes: {}",
+                end, err
+            ));
+        }
+    }
+
+    async fn check_metadata(&mut self) {
+        let metadata = self
+            .read_range(
+                self.headers.json_metadata_offset,
+                self.headers.json_metadata_length,
+            )
+            .await
+            .and_then(|data| {
+                let compression = Compression::from(self.headers.internal_compression);
+                let data = decompress(data, compression)?;
+                Ok(serde_json::from_slice::<serde_json::Value>(&data)?)
+            });
+        match metadata {
+            Ok(metadata) if metadata.is_object() => {}
+            Ok(_) => self.report.error("metadata is not a JSON object".into()),
+            Err(err) => self.report.error(format!("invalid metadata: {}", err)),
+        }
+    }
+
+    async fn read_leaf(&self, entry: &TileEntry) -> anyhow::Result<Vec<TileEntry>> {
+        let in_bounds = entry
+            .offset
+            .checked_add(entry.length)
+            .is_some_and(|end| end <= self.headers.leaf_directory_length);
+        if entry.length == 0 || !in_bounds {
+            anyhow::bail!(
+                "offset {} with length {} is outside the leaf directory section",
+                entry.offset,
+                entry.length
+            );
+        }
+        let data = self
+            .read_range(
+                self.headers.leaf_directory_offset + entry.offset,
+                entry.length,
+            )
+            .await?;
+        get_entries(data, Compression::from(self.headers.internal_compression))
+    }
+
+    fn check_tile_entry(&mut self, location: &str, entry: &TileEntry) {
+        let in_bounds = entry
+            .offset
+            .checked_add(entry.length)
+            .is_some_and(|end| end <= self.headers.tile_data_length);
+        if entry.length == 0 || !in_bounds {
+            self.report.error(format!(
+                "{}: tile data at offset {} with length {} is outside the tile data section",
+                location, entry.offset, entry.length
+            ));
+        }
+        if entry.tile_id < self.next_tile_id {
+            self.report.error(format!(
+                "{}: tile id {} overlaps the previous entries, which end at {}",
+                location, entry.tile_id, self.next_tile_id
+            ));
+        }
+        let last_tile_id = entry.tile_id.checked_add(entry.run_length - 1);
+        if last_tile_id.is_none_or(|id| tile_id_to_zxy(id).is_err()) {
+            self.report.error(format!(
+                "{}: run of {} tiles from tile id {} exceeds the maximum zoom level",
+                location, entry.run_length, entry.tile_id
+            ));
+        }
+        self.next_tile_id = self
+            .next_tile_id
+            .max(entry.tile_id.saturating_add(entry.run_length));
+
+        if self.contents.insert(entry.offset) {
+            if self.headers.clustered == 1 && entry.offset < self.tile_data_end {
+                self.report.error(format!(
+                    "{}: tile data at offset {} is out of order in a clustered archive",
+                    location, entry.offset
+                ));
+            }
+            self.tile_data_end = self
+                .tile_data_end
+                .max(entry.offset.saturating_add(entry.length));
+        }
+        self.report.tile_entries += 1;
+        self.report.addressed_tiles += entry.run_length;
+    }
+
+    // Walk the directories depth first, which visits the entries in tile id order. Leaf
+    // directories are read at most once and at most MAX_LEAF_DEPTH levels deep, so that
+    // leaves pointing back at themselves or their ancestors are reported instead of
+    // walked forever.
+    async fn check_directories(&mut self, root: Vec<TileEntry>) {
+        let mut directories = vec![("root directory".to_string(), root, 0)];
+        let mut visited = HashSet::default();
+        while let Some((name, entries, index)) = directories.last_mut() {
+            let Some(entry) = entries.get(*index).cloned() else {
+                directories.pop();
+                continue;
+            };
+            if *index == 0 {
+                self.report.directories += 1;
+            }
+            let location = format!("{} entry {}", name, index);
+            if *index > 0 && entry.tile_id <= entries[*index - 1].tile_id {
+                self.report.error(format!(
+                    "{}: tile id {} is not greater than the previous entry",
+                    location, entry.tile_id
+                ));
+            }
+            *index += 1;
+            if entry.run_length > 0 {
+                self.check_tile_entry(&location, &entry);
+                continue;
+            }
+            if !visited.insert((entry.offset, entry.length)) {
+                self.report.error(format!(
+                    "{}: leaf directory at offset {} is referenced more than once",
+                    location, entry.offset
+                ));
+                continue;
+            }
+            if directories.len() > MAX_LEAF_DEPTH {
+                self.report.error(format!(
+                    "{}: leaf directories are nested deeper than {} levels",
+                    location, MAX_LEAF_DEPTH
+                ));
+                continue;
+            }
+            match self.read_leaf(&entry).await {
+                Ok(leaf) => {
+                    if leaf
+                        .first()
+                        .is_some_and(|first| first.tile_id < entry.tile_id)
+                    {
+                        self.report.error(format!(
+                            "{}: leaf directory starts before tile id {}",
+                            location, entry.tile_id
+                        ));
+                    }
+                    let name = format!("leaf directory at offset {}", entry.offset);
+                    directories.push((name, leaf, 0));
+                }
+                Err(err) => self
+                    .report
+                    .error(format!("{}: invalid leaf directory: {}", location, err)),
+            }
+        }
+    }
+
+    fn check_counts(&mut self) {
+        // Counts of zero mean unknown
+        let counts = [
+            (
+                "addressed tiles",
+                self.headers.num_addressed_tiles,
+                self.report.addressed_tiles,
+            ),
+            (
+                "tile entries",
+                self.headers.num_tile_entries,
+                self.report.tile_entries,
+            ),
+            (
+                "tile contents",
+                self.headers.num_tile_contents,
+                self.report.tile_contents,
+            ),
+        ];
+        for (name, expected, actual) in counts {
+            if expected != 0 && expected != actual {
+                self.report.error(format!(
+                    "header has {} {} but the directories have {}",
+                    expected, name, actual
+                ));
+            }
+        }
+    }
+}
+
+// Check that an archive is complete and its directories are consistent with its header
+pub async fn verify_archive<T: Fetcher>(path: &str, client: &T) -> VerifyReport {
+    let mut report = VerifyReport {
+        path: path.into(),
+        ..Default::default()
+    };
+    let data = match client.get_data_range(path, 0, 16384).await {
+        Ok((data, _)) => data,
+        Err(err) => {
+            report.error(format!("unable to read the header: {}", err));
+            return report;
+        }
+    };
+    let headers = match data.get(..HEADER_SIZE_BYTES).map(Headers::from_bytes) {
+        Some(Ok(headers)) => headers,
+        Some(Err(err)) => {
+            report.error(format!("invalid header: {}", err));
+            return report;
+        }
+        None => {
+            report.error(format!(
+                "archive is shorter than the {} byte header",
+                HEADER_SIZE_BYTES
+            ));
+            return report;
+        }
+    };
+    let root_range = headers.root_directory_offset as usize
+        ..headers
+            .root_directory_offset
+            .saturating_add(headers.root_directory_length) as usize;
+    let root = data
+        .get(root_range.clone())
+        .ok_or_else(|| anyhow::anyhow!("root directory is out of bounds"))
+        .and_then(|_| {
+            get_entries(
+                data.slice(root_range),
+                Compression::from(headers.internal_compression),
+            )
+        });
+
+    let mut verifier = Verifier {
+        path,
+        client,
+        headers,
+        report,
+        next_tile_id: 0,
+        contents: HashSet::default(),
+        tile_data_end: 0,
+    };
+    verifier.check_sections().await;
+    verifier.check_metadata().await;
+    match root {
+        Ok(root) => {
+            verifier.check_directories(root).await;
+            verifier.report.tile_contents = verifier.contents.len() as u64;
+            verifier.check_counts();
+        }
+        Err(err) => verifier
+            .report
+            .error(format!("invalid root directory: {}", err)),
+    }
+    let mut report = verifier.report;
+    report.valid = report.error_count == 0;
+    report
+}
+
+#[tokio::test]
+async fn test_verify_archive() {
+    use crate::fetcher::LocalFetcher;
+    use crate::helpers::encode_entries;
+    use crate::models::TileType;
+    use crate::writer::PMTilesWriter;
+
+    let client = LocalFetcher::new();
+    let report = verify_archive("../../testdata/data/data.pmtiles", &client).await;
+    assert!(report.valid, "{:?}", report.errors);
+    assert_eq!(report.directories, 1);
+    assert_eq!(report.addressed_tiles, 28);
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    for x in 0..150 {
+        for y in 0..150 {
+            writer
+                .add_tile(8, x, y, format!("{}/{}", x, y).as_bytes())
+                .unwrap();
+        }
+    }
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let headers = writer.finish(&mut file.as_file()).unwrap();
+    let path = file.path().to_str().unwrap();
+    let report = verify_archive(path, &client).await;
+    assert!(report.valid, "{:?}", report.errors);
+    assert!(report.directories > 1);
+    assert_eq!(report.addressed_tiles, 150 * 150);
+    assert_eq!(report.tile_contents, 150 * 150);
+
+    // Cut off the end of the tile data
+    let length = headers.tile_data_offset + headers.tile_data_length;
+    file.as_file().set_len(length - 10).unwrap();
+    let report = verify_archive(path, &client).await;
+    assert!(!report.valid);
+    assert!(report.errors[0].starts_with("archive is truncated"));
+
+    // Break a leaf directory
+    file.as_file().set_len(length).unwrap();
+    let mut data = std::fs::read(path).unwrap();
+    let leaf_offset = headers.leaf_directory_offset as usize;
+    data[leaf_offset..leaf_offset + 8].fill(0xff);
+    std::fs::write(path, &data).unwrap();
+    let report = verify_archive(path, &client).await;
+    assert!(!report.valid);
+    assert!(report
+        .errors
+        .iter()
+        .any(|err| err.contains("invalid leaf directory")));
+
+    // A leaf directory pointing back at itself
+    let mut leaf = TileEntry {
+        tile_id: 0,
+        offset: 0,
+        length: 0,
+        run_length: 0,
+    };
+    while leaf.length != encode_entries(&[leaf.clone()]).len() as u64 {
+        leaf.length = encode_entries(&[leaf.clone()]).len() as u64;
+    }
+    let directory = encode_entries(&[leaf.clone()]);
+    let headers = Headers {
+        spec_version: 3,
+        root_directory_offset: HEADER_SIZE_BYTES as u64,
+        root_directory_length: directory.len() as u64,
+        json_metadata_offset: (HEADER_SIZE_BYTES + directory.len()) as u64,
+        leaf_directory_offset: (HEADER_SIZE_BYTES + directory.len()) as u64,
+        leaf_directory_length: leaf.length,
+        tile_data_offset: (HEADER_SIZE_BYTES + 2 * directory.len()) as u64,
+        internal_compression: 1,
+        tile_compression: 1,
+        tile_type: 1,
+        ..Default::default()
+    };
+    let mut data = headers.to_bytes().unwrap();
+    data.extend(&directory);
+    data.extend(&directory);
+    std::fs::write(path, &data).unwrap();
+    let report = verify_archive(path, &client).await;
+    assert!(!report.valid);
+    assert!(report
+        .errors
+        .iter()
+        .any(|err| err.contains("referenced more than once")));
+}
@@ -0,0 +1,380 @@
+use crate::compress::{compress, Compression};
+use crate::helpers::{encode_entries, zxy_to_tile_id};
+use crate::models::{Headers, TileEntry, TileType, HEADER_SIZE_BYTES};
+use crate::utils::tile_bounds;
+use fxhash::FxHashMap as HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use xxhash_rust::xxh3::xxh3_128;
+
+// The header and the root directory have to fit in the first 16 KiB of an archive
+const MAX_ROOT_DIRECTORY_BYTES: usize = 16384 - HEADER_SIZE_BYTES;
+
+struct PendingTile {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+}
+
+// Streaming PMTiles v3 archive writer.
+//
+// Tiles may be added in any order. Tile data is spooled to a temporary file with
+// identical contents stored only once, and the archive is laid out when calling
+// `finish`: tiles are clustered by tile id, consecutive tiles sharing the same
+// contents are merged into run-length entries and the directory is split into
+// leaf directories when the root directory would not fit in the first 16 KiB.
+//
+// Tile data is written as-is and must already be encoded with the tile compression
+// given to the writer.
+pub struct PMTilesWriter {
+    tile_data: BufWriter<File>,
+    tile_data_length: u64,
+    tiles: Vec<PendingTile>,
+    contents: HashMap<u128, (u64, u64)>,
+    tile_type: TileType,
+    tile_compression: Compression,
+    internal_compression: Compression,
+    metadata: serde_json::Value,
+    min_zoom: u8,
+    max_zoom: u8,
+    tile_extent: Option<[f64; 4]>,
+    bounds: Option<[f64; 4]>,
+    center: Option<(f64, f64, u8)>,
+}
+
+impl PMTilesWriter {
+    pub fn new(tile_type: TileType, tile_compression: Compression) -> anyhow::Result<Self> {
+        Ok(PMTilesWriter {
+            tile_data: BufWriter::new(tempfile::tempfile()?),
+            tile_data_length: 0,
+            tiles: Vec::new(),
+            contents: HashMap::default(),
+            tile_type,
+            tile_compression,
+            internal_compression: Compression::Gzip,
+            metadata: serde_json::Value::Object(serde_json::Map::new()),
+            min_zoom: u8::MAX,
+            max_zoom: 0,
+            tile_extent: None,
+            bounds: None,
+            center: None,
+        })
+    }
+
+    pub fn set_metadata(&mut self, metadata: serde_json::Value) {
+        self.metadata = metadata;
+    }
+
+    // Bounds default to the extent of the written tiles
+    pub fn set_bounds(&mut self, min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) {
+        self.bounds = Some([min_lon, min_lat, max_lon, max_lat]);
+    }
+
+    // Center defaults to the middle of the bounds at the minimum zoom level
+    pub fn set_center(&mut self, lon: f64, lat: f64, zoom: u8) {
+        self.center = Some((lon, lat, zoom));
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn add_tile(&mut self, z: u8, x: u64, y: u64, data: &[u8]) -> anyhow::Result<()> {
+        let tile_id = zxy_to_tile_id(z as u64, x, y)?;
+        let hash = xxh3_128(data);
+        let (offset, length) = match self.contents.get(&hash) {
+            Some(content) => *content,
+            None => {
+                self.tile_data.write_all(data)?;
+                let content = (self.tile_data_length, data.len() as u64);
+                self.tile_data_length += data.len() as u64;
+                self.contents.insert(hash, content);
+                content
+            }
+        };
+        self.tiles.push(PendingTile {
+            tile_id,
+            offset,
+            length,
+        });
+
+        self.min_zoom = self.min_zoom.min(z);
+        self.max_zoom = self.max_zoom.max(z);
+        let [min_lon, min_lat, max_lon, max_lat] = tile_bounds(z, x, y);
+        self.tile_extent = Some(match self.tile_extent {
+            Some(extent) => [
+                extent[0].min(min_lon),
+                extent[1].min(min_lat),
+                extent[2].max(max_lon),
+                extent[3].max(max_lat),
+            ],
+            None => [min_lon, min_lat, max_lon, max_lat],
+        });
+        Ok(())
+    }
+
+    // Lay out the archive and write it to the output, returning the written headers
+    pub fn finish<W: Write>(mut self, out: &mut W) -> anyhow::Result<Headers> {
+        let mut tile_data = self
+            .tile_data
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+        self.tiles.sort_unstable_by_key(|tile| tile.tile_id);
+        if let Some(duplicate) = self
+            .tiles
+            .windows(2)
+            .find(|pair| pair[0].tile_id == pair[1].tile_id)
+        {
+            anyhow::bail!("tile id {} was added more than once", duplicate[0].tile_id);
+        }
+
+        // Order tile contents by the first tile referring to them and merge runs
+        let mut entries: Vec<TileEntry> = Vec::new();
+        let mut placed: HashMap<u64, u64> = HashMap::default();
+        let mut copies: Vec<(u64, u64)> = Vec::new();
+        let mut tile_data_length = 0;
+        for tile in &self.tiles {
+            let offset = *placed.entry(tile.offset).or_insert_with(|| {
+                copies.push((tile.offset, tile.length));
+                tile_data_length += tile.length;
+                tile_data_length - tile.length
+            });
+            if let Some(last) = entries.last_mut() {
+                if last.offset == offset && last.tile_id + last.run_length == tile.tile_id {
+                    last.run_length += 1;
+                    continue;
+                }
+            }
+            entries.push(TileEntry {
+                tile_id: tile.tile_id,
+                offset,
+                length: tile.length,
+                run_length: 1,
+            });
+        }
+
+        let (root, leaves) = optimize_directories(&entries, self.internal_compression)?;
+        let metadata = compress(
+            &serde_json::to_vec(&self.metadata)?,
+            self.internal_compression,
+        )?;
+
+        let [min_lon, min_lat, max_lon, max_lat] =
+            self.bounds
+                .or(self.tile_extent)
+                .unwrap_or([-180., -85.0511287, 180., 85.0511287]);
+        let min_zoom = self.min_zoom.min(self.max_zoom);
+        let (center_lon, center_lat, center_zoom) =
+            self.center
+                .unwrap_or(((min_lon + max_lon) / 2., (min_lat + max_lat) / 2., min_zoom));
+
+        let root_directory_offset = HEADER_SIZE_BYTES as u64;
+        let json_metadata_offset = root_directory_offset + root.len() as u64;
+        let leaf_directory_offset = json_metadata_offset + metadata.len() as u64;
+        let tile_data_offset = leaf_directory_offset + leaves.len() as u64;
+        let headers = Headers {
+            spec_version: 3,
+            root_directory_offset,
+            root_directory_length: root.len() as u64,
+            json_metadata_offset,
+            json_metadata_length: metadata.len() as u64,
+            leaf_directory_offset,
+            leaf_directory_length: leaves.len() as u64,
+            tile_data_offset,
+            tile_data_length,
+            num_addressed_tiles: self.tiles.len() as u64,
+            num_tile_entries: entries.len() as u64,
+            num_tile_contents: copies.len() as u64,
+            clustered: 1,
+            internal_compression: self.internal_compression.into(),
+            tile_compression: self.tile_compression.into(),
+            tile_type: self.tile_type.into(),
+            min_zoom,
+            max_zoom: self.max_zoom,
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+            center_zoom,
+            center_lon,
+            center_lat,
+            etag: None,
+        };
+
+        out.write_all(&headers.to_bytes()?)?;
+        out.write_all(&root)?;
+        out.write_all(&metadata)?;
+        out.write_all(&leaves)?;
+
+        tile_data.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(tile_data);
+        let mut position = 0;
+        let mut buffer = Vec::new();
+        for (offset, length) in copies {
+            if offset != position {
+                reader.seek(SeekFrom::Start(offset))?;
+            }
+            buffer.resize(length as usize, 0);
+            reader.read_exact(&mut buffer)?;
+            out.write_all(&buffer)?;
+            position = offset + length;
+        }
+        out.flush()?;
+        Ok(headers)
+    }
+}
+
+fn build_directories(
+    entries: &[TileEntry],
+    leaf_size: usize,
+    compression: Compression,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut root_entries = Vec::new();
+    let mut leaves = Vec::new();
+    for chunk in entries.chunks(leaf_size) {
+        let leaf = compress(&encode_entries(chunk), compression)?;
+        root_entries.push(TileEntry {
+            tile_id: chunk[0].tile_id,
+            offset: leaves.len() as u64,
+            length: leaf.len() as u64,
+            run_length: 0,
+        });
+        leaves.extend_from_slice(&leaf);
+    }
+    let root = compress(&encode_entries(&root_entries), compression)?;
+    Ok((root, leaves))
+}
+
+// Split the entries into leaf directories until the root directory is small enough
+fn optimize_directories(
+    entries: &[TileEntry],
+    compression: Compression,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if entries.len() < 16384 {
+        let root = compress(&encode_entries(entries), compression)?;
+        if root.len() <= MAX_ROOT_DIRECTORY_BYTES {
+            return Ok((root, Vec::new()));
+        }
+    }
+    let mut leaf_size = (entries.len() / 3500).max(4096);
+    loop {
+        let (root, leaves) = build_directories(entries, leaf_size, compression)?;
+        if root.len() <= MAX_ROOT_DIRECTORY_BYTES {
+            return Ok((root, leaves));
+        }
+        leaf_size += leaf_size / 5;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_write_archive() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::LocalFetcher;
+    use crate::helpers::get_headers;
+    use crate::{get_metadata, get_tile};
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    writer.set_metadata(serde_json::json!({"name": "test", "vector_layers": []}));
+    // Added out of order, with a run of identical tiles and a repeated content
+    writer.add_tile(2, 0, 0, b"first").unwrap();
+    for (x, y) in [(1, 1), (0, 0), (0, 1), (1, 0)] {
+        writer.add_tile(1, x, y, b"second").unwrap();
+    }
+    writer.add_tile(0, 0, 0, b"first").unwrap();
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let written = writer.finish(&mut file.as_file()).unwrap();
+    assert_eq!(written.num_addressed_tiles, 6);
+    assert_eq!(written.num_tile_entries, 3);
+    assert_eq!(written.num_tile_contents, 2);
+
+    let client = LocalFetcher::new();
+    let path = file.path().to_str().unwrap();
+    let (headers, entries) = get_headers(path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(headers.num_addressed_tiles, 6);
+    assert_eq!(headers.clustered, 1);
+    assert_eq!(headers.min_zoom, 0);
+    assert_eq!(headers.max_zoom, 2);
+    assert_eq!(headers.min_lon, -180.);
+    assert_eq!(headers.max_lat, 85.0511288);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[1].run_length, 4);
+    assert_eq!(entries[0].offset, entries[2].offset);
+
+    let tile = get_tile(1, 1, 0, path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"second"[..]);
+    let tile = get_tile(2, 0, 0, path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"first"[..]);
+
+    let (_, metadata) = get_metadata(path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(metadata["name"], "test");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_write_archive_with_leaves() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::LocalFetcher;
+    use crate::get_tile;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    for x in 0..160 {
+        for y in 0..160 {
+            writer
+                .add_tile(8, x, y, format!("{}/{}", x, y).as_bytes())
+                .unwrap();
+        }
+    }
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let headers = writer.finish(&mut file.as_file()).unwrap();
+    assert_eq!(headers.num_tile_entries, 25600);
+    assert!(headers.leaf_directory_length > 0);
+    assert!(headers.root_directory_length as usize <= MAX_ROOT_DIRECTORY_BYTES);
+
+    let client = LocalFetcher::new();
+    let path = file.path().to_str().unwrap();
+    for (x, y) in [(0, 0), (37, 151), (159, 159)] {
+        let tile = get_tile(8, x, y, path, &client, None as Option<&InMemoryCache>)
+            .await
+            .unwrap();
+        assert_eq!(tile, format!("{}/{}", x, y).as_bytes());
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_read_sparse_archives() {
+    use crate::cache::InMemoryCache;
+    use crate::fetcher::LocalFetcher;
+    use crate::{get_tile, PMTilesError};
+
+    // An archive without tiles has an empty root directory
+    let writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    let empty = tempfile::NamedTempFile::new().unwrap();
+    writer.finish(&mut empty.as_file()).unwrap();
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    writer.add_tile(1, 1, 1, b"tile").unwrap();
+    let single = tempfile::NamedTempFile::new().unwrap();
+    writer.finish(&mut single.as_file()).unwrap();
+
+    let client = LocalFetcher::new();
+    let cache = None as Option<&InMemoryCache>;
+    let res = get_tile(0, 0, 0, empty.path().to_str().unwrap(), &client, cache).await;
+    assert!(matches!(res, Err(PMTilesError::OutOfBounds())));
+    let path = single.path().to_str().unwrap();
+    for (x, y) in [(0, 0), (0, 1), (1, 0)] {
+        let res = get_tile(1, x, y, path, &client, cache).await;
+        assert!(matches!(res, Err(PMTilesError::OutOfBounds())));
+    }
+    let tile = get_tile(1, 1, 1, path, &client, cache).await.unwrap();
+    assert_eq!(tile, &b"tile"[..]);
+}
@@ -2,6 +2,8 @@ use crate::{
     error::APIError,
     utils::{canonicalize_local_path, join_path, pick_random_element, trim_slash},
 };
+use pmtiles_core::httputils::is_http_path;
+use pmtiles_core::s3utils::is_s3_path;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use url::Url;
@@ -14,12 +16,59 @@ pub struct PathsConfig {
     pub sprites: Option<String>,
     pub icons: Option<String>,
     pub pmtiles: Option<String>,
+    pub mbtiles: Option<String>,
     pub styles: Option<String>,
 }
 #[derive(Serialize, Deserialize)]
 pub struct OptionsConfig {
     pub paths: PathsConfig,
     pub domains: Vec<String>,
+    pub cache: Option<CacheConfig>,
+    pub tile_cache: Option<TileCacheConfig>,
+    pub disk_cache: Option<DiskCacheConfig>,
+    pub cache_control: Option<RoutesCacheControlConfig>,
+    // Read local archives through memory mappings instead of reading each range from disk
+    pub mmap: Option<bool>,
+}
+// Bounds for the in-memory cache, which is unbounded if not configured
+#[derive(Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub max_size_mb: Option<usize>,
+    pub ttl_seconds: Option<u64>,
+}
+// Budget for caching tile contents, with tiles up to pin_max_zoom kept permanently
+#[derive(Serialize, Deserialize)]
+pub struct TileCacheConfig {
+    pub max_size_mb: Option<usize>,
+    pub pin_max_zoom: Option<u8>,
+}
+// Directory for keeping cached data across restarts, behind the in-memory cache
+#[derive(Serialize, Deserialize)]
+pub struct DiskCacheConfig {
+    pub path: String,
+    pub max_size_mb: Option<usize>,
+}
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CacheControlConfig {
+    pub max_age: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+}
+// Cache-Control settings per route, responses of routes without them get no header
+#[derive(Serialize, Deserialize, Default)]
+pub struct RoutesCacheControlConfig {
+    pub tiles: Option<CacheControlConfig>,
+    pub tilejson: Option<CacheControlConfig>,
+    pub styles: Option<CacheControlConfig>,
+    pub fonts: Option<CacheControlConfig>,
+    pub sprites: Option<CacheControlConfig>,
+}
+#[derive(Clone, Copy)]
+pub enum Route {
+    Tiles,
+    TileJson,
+    Styles,
+    Fonts,
+    Sprites,
 }
 #[derive(Serialize, Deserialize)]
 pub struct StyleConfig {
@@ -27,7 +76,33 @@ pub struct StyleConfig {
 }
 #[derive(Serialize, Deserialize)]
 pub struct DataConfig {
-    pub pmtiles: String,
+    pub pmtiles: Option<String>,
+    pub mbtiles: Option<String>,
+    // Overrides options.cache_control.tiles for the tiles of this tileset
+    pub cache_control: Option<CacheControlConfig>,
+}
+
+impl CacheControlConfig {
+    pub fn header_value(&self) -> Option<String> {
+        let mut directives = Vec::new();
+        if let Some(max_age) = self.max_age {
+            directives.push("public".to_string());
+            directives.push(format!("max-age={}", max_age));
+        }
+        if let Some(stale_while_revalidate) = self.stale_while_revalidate {
+            directives.push(format!("stale-while-revalidate={}", stale_while_revalidate));
+        }
+        if directives.is_empty() {
+            None
+        } else {
+            Some(directives.join(", "))
+        }
+    }
+}
+
+pub enum TilesetSource {
+    PMTiles(String),
+    MBTiles(String),
 }
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
@@ -48,19 +123,72 @@ impl ServerConfig {
         let fonts_prefix = &self.options.paths.fonts.clone().unwrap_or("fonts".into());
         Ok(format!("{}/{}/{}/{}.pbf", root, fonts_prefix, font, range))
     }
-    pub fn get_tileset_path(&self, tileset: &str) -> anyhow::Result<String> {
+    pub fn get_sprite_path(&self, sprite: &str) -> anyhow::Result<String> {
+        let root = canonicalize_local_path(&self.options.paths.root.clone().unwrap_or(".".into()))?;
+        let sprites_prefix = &self
+            .options
+            .paths
+            .sprites
+            .clone()
+            .unwrap_or("sprites".into());
+        Ok(format!("{}/{}/{}", root, sprites_prefix, sprite))
+    }
+    // Icons directory for generated sprites, if icons are configured
+    pub fn get_icons_path(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let icons_prefix = match &self.options.paths.icons {
+            Some(icons_prefix) if !icons_prefix.is_empty() => icons_prefix,
+            _ => return Ok(None),
+        };
+        let root = canonicalize_local_path(&self.options.paths.root.clone().unwrap_or(".".into()))?;
+        Ok(Some(format!("{}/{}/{}", root, icons_prefix, name)))
+    }
+    pub fn get_tileset_source(&self, tileset: &str) -> anyhow::Result<TilesetSource> {
         let root = canonicalize_local_path(&self.options.paths.root.clone().unwrap_or(".".into()))?;
         let found = self
             .data
             .get(tileset)
             .ok_or_else(|| APIError::NotFound(Some("tileset does not exist in config".into())))?;
-        let pmtiles_prefix = &self.options.paths.pmtiles;
-        if let Some(pmtiles_prefix) = pmtiles_prefix {
-            Ok(format!("{}/{}/{}", root, pmtiles_prefix, found.pmtiles))
-        } else {
-            Ok(format!("{}/{}", root, found.pmtiles))
+        let resolve = |prefix: &Option<String>, file: &str| {
+            if is_http_path(file) {
+                file.to_string()
+            } else if let Some(prefix) = prefix {
+                format!("{}/{}/{}", root, prefix, file)
+            } else {
+                format!("{}/{}", root, file)
+            }
+        };
+        match (&found.pmtiles, &found.mbtiles) {
+            (Some(pmtiles), _) => Ok(TilesetSource::PMTiles(resolve(
+                &self.options.paths.pmtiles,
+                pmtiles,
+            ))),
+            (None, Some(mbtiles)) => {
+                let path = resolve(&self.options.paths.mbtiles, mbtiles);
+                if is_s3_path(&path) || is_http_path(&path) {
+                    anyhow::bail!("mbtiles files have to be stored on a local disk");
+                }
+                Ok(TilesetSource::MBTiles(path))
+            }
+            (None, None) => anyhow::bail!("tileset {} has no pmtiles or mbtiles file", tileset),
         }
     }
+    pub fn get_cache_control(&self, route: Route, tileset: Option<&str>) -> Option<String> {
+        let tileset_config = match route {
+            Route::Tiles => tileset
+                .and_then(|tileset| self.data.get(tileset))
+                .and_then(|data| data.cache_control.as_ref()),
+            _ => None,
+        };
+        let routes = self.options.cache_control.as_ref();
+        let route_config = routes.and_then(|routes| match route {
+            Route::Tiles => routes.tiles.as_ref(),
+            Route::TileJson => routes.tilejson.as_ref(),
+            Route::Styles => routes.styles.as_ref(),
+            Route::Fonts => routes.fonts.as_ref(),
+            Route::Sprites => routes.sprites.as_ref(),
+        });
+        tileset_config.or(route_config)?.header_value()
+    }
     pub fn get_domain(&self) -> String {
         let default_domain = "".to_string();
         let picked_domain: String = {
@@ -68,7 +196,7 @@ impl ServerConfig {
                 domain
             } else {
                 pick_random_element(&self.options.domains)
-                    .unwrap_or_else(|| &default_domain)
+                    .unwrap_or(&default_domain)
                     .to_owned()
             }
         };
@@ -81,7 +209,7 @@ pub fn get_data_path(source: &str, url: &str, config: &ServerConfig) -> String {
     if let Ok(parsed) = parsed {
         // Parse the path part and restore the curly braces that get url-encoded
         let prefix = match parsed.scheme() {
-            "pmtiles" => config.options.paths.pmtiles.as_ref(),
+            "pmtiles" | "mbtiles" => config.options.paths.pmtiles.as_ref(),
             _ => None,
         };
         let path = source.to_string();
@@ -92,10 +220,10 @@ pub fn get_data_path(source: &str, url: &str, config: &ServerConfig) -> String {
                 trim_slash(&path)
             }
         };
-        prefix_with_home(&mut prefixed_path, &config, false, true);
+        prefix_with_home(&mut prefixed_path, config, false, true);
         return prefixed_path;
     }
-    return "".to_owned();
+    "".to_owned()
 }
 
 pub fn get_path(url: &str, config: &ServerConfig) -> String {
@@ -127,10 +255,10 @@ pub fn get_path(url: &str, config: &ServerConfig) -> String {
                 trim_slash(&path)
             }
         };
-        prefix_with_home(&mut prefixed_path, &config, false, true);
+        prefix_with_home(&mut prefixed_path, config, false, true);
         return prefixed_path;
     }
-    return "".to_owned();
+    "".to_owned()
 }
 
 pub fn prefix_with_home(
@@ -166,7 +294,27 @@ fn test_config_deserialize() {
     assert_eq!(cfg.options.paths.icons, Some("".into()));
     assert_eq!(
         cfg.data.get("cadastral_fi").unwrap().pmtiles,
-        "cadastral_fi.pmtiles"
+        Some("cadastral_fi.pmtiles".into())
     );
+    assert_eq!(cfg.data.get("cadastral_fi").unwrap().mbtiles, None);
     assert_eq!(cfg.styles.get("cadastral").unwrap().style, "cadastral.json");
+    assert_eq!(
+        cfg.get_cache_control(Route::Tiles, Some("cadastral_fi")),
+        Some("public, max-age=86400, stale-while-revalidate=3600".into())
+    );
+    assert_eq!(
+        cfg.get_cache_control(Route::Tiles, Some("other")),
+        Some("public, max-age=3600".into())
+    );
+    assert_eq!(
+        cfg.get_cache_control(Route::Styles, None),
+        Some("stale-while-revalidate=60".into())
+    );
+    assert_eq!(cfg.get_cache_control(Route::Fonts, None), None);
+    let cache = cfg.options.cache.unwrap();
+    assert_eq!(cache.max_size_mb, Some(64));
+    assert_eq!(cache.ttl_seconds, None);
+    let tile_cache = cfg.options.tile_cache.unwrap();
+    assert_eq!(tile_cache.max_size_mb, None);
+    assert_eq!(tile_cache.pin_max_zoom, Some(6));
 }
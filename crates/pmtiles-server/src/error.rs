@@ -5,8 +5,6 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum APIError {
-    #[error("validation error:{0}")]
-    Validation(String),
     #[error("internal error: {0}")]
     Internal(String),
     #[error("other error")]
@@ -33,6 +31,7 @@ impl From<PMTilesError> for APIError {
             PMTilesError::BadRequest(err) => APIError::BadRequest(Some(err)),
             PMTilesError::Internal(err) => APIError::Internal(err),
             PMTilesError::CacheError(err) => APIError::Internal(err.to_string()),
+            PMTilesError::EtagMismatch() => APIError::Internal("tileset changed".into()),
         }
     }
 }
@@ -42,6 +41,8 @@ impl From<FetcherError> for APIError {
         match err {
             FetcherError::NotFound() => APIError::NotFound(None),
             FetcherError::S3Error(_) => APIError::Internal("failed to fetch S3 data".into()),
+            FetcherError::HttpError(_) => APIError::Internal("failed to fetch HTTP data".into()),
+            FetcherError::EtagMismatch() => APIError::Internal("data changed".into()),
             FetcherError::Other(err) => APIError::Other(err),
         }
     }
@@ -50,7 +51,6 @@ impl From<FetcherError> for APIError {
 impl IntoResponse for APIError {
     fn into_response(self) -> axum::response::Response {
         let (status, payload) = match &self {
-            APIError::Validation(_) => (StatusCode::UNPROCESSABLE_ENTITY, Json(self)),
             APIError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(self)),
             APIError::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(self)),
             APIError::NotFound(_) => (StatusCode::NOT_FOUND, Json(self)),
@@ -72,12 +72,6 @@ impl Serialize for APIError {
         let mut description = None;
         let mut name = "Internal server error";
         match self {
-            APIError::Validation(err) => {
-                code = 422;
-                name = "Unprocessable entity";
-                description = Some("Validation failed");
-                errors.push(err.into());
-            }
             APIError::Internal(_) => {}
             APIError::Other(_) => {}
             APIError::NotFound(err) => {
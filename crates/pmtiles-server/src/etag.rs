@@ -0,0 +1,76 @@
+use crate::error::APIError;
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::Response;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Strong ETag derived from a hash of the response body
+pub fn content_etag(data: &[u8]) -> String {
+    format!("\"{:016x}\"", xxh3_64(data))
+}
+
+// ETag of a representation sent with a content coding, which must differ from the
+// ETag of the unencoded data
+pub fn encoded_etag(etag: &str, encoding: &str) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding)
+}
+
+// Check whether If-None-Match lists the ETag, using the weak comparison required for it
+pub fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+pub fn not_modified(etag: &str) -> Result<Response, APIError> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .body(Body::empty())
+        .map_err(|err| {
+            tracing::error!("{}", err);
+            APIError::Internal("invalid etag".into())
+        })
+}
+
+// Respond with the data and its content hash as the ETag, or with 304 Not Modified if
+// the client already has it
+pub fn etag_response(
+    headers: &HeaderMap,
+    content_type: &str,
+    data: Bytes,
+) -> Result<Response, APIError> {
+    let etag = content_etag(&data);
+    if is_not_modified(headers, &etag) {
+        return not_modified(&etag);
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ETAG, etag)
+        .body(Body::from(data))
+        .map_err(|err| {
+            tracing::error!("{}", err);
+            APIError::Internal("invalid response data".into())
+        })
+}
+
+#[test]
+fn test_is_not_modified() {
+    let etag = content_etag(b"tile");
+    let mut headers = HeaderMap::new();
+    assert!(!is_not_modified(&headers, &etag));
+    headers.insert(
+        header::IF_NONE_MATCH,
+        format!("\"other\", W/{}", etag).parse().unwrap(),
+    );
+    assert!(is_not_modified(&headers, &etag));
+    assert!(!is_not_modified(&headers, &encoded_etag(&etag, "gzip")));
+    headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+    assert!(is_not_modified(&headers, &etag));
+    assert_eq!(encoded_etag("\"abc\"", "br"), "\"abc-br\"");
+}
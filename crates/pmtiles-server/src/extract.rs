@@ -0,0 +1,48 @@
+use crate::server::create_fetcher;
+use pmtiles_core::extract::{extract, Region};
+use pmtiles_core::fetcher::Fetcher;
+use pmtiles_core::v2::upgrade;
+use std::fs::File;
+use std::io::BufWriter;
+
+// Extract the tiles within a bounding box or the polygons of a GeoJSON file into a local archive
+pub async fn extract_archive(
+    input: &str,
+    output: &str,
+    bbox: Option<Vec<f64>>,
+    region: Option<String>,
+    min_zoom: Option<u8>,
+    max_zoom: Option<u8>,
+) -> anyhow::Result<()> {
+    let fetcher = create_fetcher().await;
+    let region = match (bbox.as_deref(), region) {
+        (Some(&[min_lon, min_lat, max_lon, max_lat]), _) => {
+            Region::BBox([min_lon, min_lat, max_lon, max_lat])
+        }
+        (Some(_), _) => anyhow::bail!("bounding box must be min_lon,min_lat,max_lon,max_lat"),
+        (_, Some(region_path)) => {
+            let (data, _) = fetcher.get_data(&region_path).await?;
+            Region::from_geojson(&serde_json::from_slice(&data)?)?
+        }
+        _ => anyhow::bail!("provide a bounding box or a GeoJSON region"),
+    };
+    let mut out = BufWriter::new(File::create(output)?);
+    let headers = extract(input, &fetcher, &region, min_zoom, max_zoom, &mut out).await?;
+    tracing::info!(
+        "wrote {} tiles at zoom levels {}-{} to {}",
+        headers.num_addressed_tiles,
+        headers.min_zoom,
+        headers.max_zoom,
+        output
+    );
+    Ok(())
+}
+
+// Convert a PMTiles v2 archive into a local PMTiles v3 archive
+pub async fn upgrade_archive(input: &str, output: &str) -> anyhow::Result<()> {
+    let fetcher = create_fetcher().await;
+    let mut out = BufWriter::new(File::create(output)?);
+    let headers = upgrade(input, &fetcher, &mut out).await?;
+    tracing::info!("wrote {} tiles to {}", headers.num_addressed_tiles, output);
+    Ok(())
+}
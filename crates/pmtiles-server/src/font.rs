@@ -1,11 +1,12 @@
+use axum::body::Bytes;
 use pbf_font_tools::protobuf::{self, Message};
 use pbf_font_tools::{combine_glyphs, Glyphs};
 use pmtiles_core::{cache::Cache, fetcher::Fetcher};
 
 use crate::error::APIError;
 
-fn combine_fonts(fonts_data: Vec<Vec<u8>>) -> Result<Vec<u8>, APIError> {
-    if fonts_data.len() == 0 {
+fn combine_fonts(fonts_data: Vec<Bytes>) -> Result<Vec<u8>, APIError> {
+    if fonts_data.is_empty() {
         return Ok(Vec::new());
     }
     let glyphs = fonts_data
@@ -26,11 +27,11 @@ fn combine_fonts(fonts_data: Vec<Vec<u8>>) -> Result<Vec<u8>, APIError> {
     }
 }
 
-pub async fn fetch_fonts<F: Fetcher, C: Cache>(
+pub async fn fetch_fonts<F: Fetcher, C: Cache + ?Sized>(
     paths: Vec<String>,
     client: &F,
     cache: Option<&C>,
-) -> Result<Vec<u8>, APIError> {
+) -> Result<Bytes, APIError> {
     let key = paths.join(",");
     let cache_hit = {
         match &cache {
@@ -44,7 +45,7 @@ pub async fn fetch_fonts<F: Fetcher, C: Cache>(
             cached
         }
         None => {
-            let mut fonts_data: Vec<Vec<u8>> = Vec::with_capacity(paths.len());
+            let mut fonts_data: Vec<Bytes> = Vec::with_capacity(paths.len());
             for path in paths {
                 let (data, _) = client.get_data(&path).await.map_err(|err| {
                     tracing::error!("{}", err);
@@ -52,9 +53,9 @@ pub async fn fetch_fonts<F: Fetcher, C: Cache>(
                 })?;
                 fonts_data.push(data);
             }
-            let fonts_combined = combine_fonts(fonts_data)?;
+            let fonts_combined = Bytes::from(combine_fonts(fonts_data)?);
             if let Some(cache) = cache {
-                let res = cache.set(&key, &fonts_combined);
+                let res = cache.set(&key, fonts_combined.clone());
                 if let Err(err) = res {
                     tracing::warn!("failed to cache key {} with {}", key, err);
                 } else {
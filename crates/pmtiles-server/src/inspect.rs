@@ -0,0 +1,200 @@
+use crate::server::create_fetcher;
+use pmtiles_core::cache::InMemoryCache;
+use pmtiles_core::compress::Compression;
+use pmtiles_core::fetcher::Fetcher;
+use pmtiles_core::models::{Headers, TileType};
+use pmtiles_core::verify::verify_archive;
+use pmtiles_core::{get_metadata, iterate_tiles};
+use std::collections::{BTreeMap, HashSet};
+
+// Tile statistics gathered by walking every directory of an archive
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchiveStats {
+    pub directory_levels: usize,
+    pub tiles_per_zoom: BTreeMap<u64, u64>,
+    pub addressed_tiles: u64,
+    pub tile_contents: u64,
+    // Sum of the sizes of all addressed tiles, counting shared contents for every tile
+    pub total_size: u64,
+    // Size of the distinct tile contents actually stored
+    pub stored_size: u64,
+}
+
+impl ArchiveStats {
+    pub fn average_size(&self) -> f64 {
+        if self.addressed_tiles == 0 {
+            return 0.;
+        }
+        self.total_size as f64 / self.addressed_tiles as f64
+    }
+
+    // Addressed tiles per distinct tile contents
+    pub fn deduplication_ratio(&self) -> f64 {
+        if self.tile_contents == 0 {
+            return 1.;
+        }
+        self.addressed_tiles as f64 / self.tile_contents as f64
+    }
+}
+
+pub async fn collect_stats<F: Fetcher>(path: &str, client: &F) -> anyhow::Result<ArchiveStats> {
+    let mut tiles = iterate_tiles(path, client, None as Option<&InMemoryCache>).await?;
+    let mut stats = ArchiveStats::default();
+    let mut contents = HashSet::new();
+    while let Some(tile) = tiles.next().await? {
+        stats.directory_levels = stats.directory_levels.max(tiles.depth());
+        *stats.tiles_per_zoom.entry(tile.z).or_default() += 1;
+        stats.addressed_tiles += 1;
+        stats.total_size += tile.length;
+        if contents.insert(tile.offset) {
+            stats.tile_contents += 1;
+            stats.stored_size += tile.length;
+        }
+    }
+    stats.directory_levels = stats.directory_levels.max(1);
+    Ok(stats)
+}
+
+fn print_headers(headers: &Headers) {
+    println!("Header");
+    println!("  spec version:           {}", headers.spec_version);
+    println!(
+        "  tile type:              {:?}",
+        TileType::from(headers.tile_type)
+    );
+    println!(
+        "  tile compression:       {:?}",
+        Compression::from(headers.tile_compression)
+    );
+    println!(
+        "  internal compression:   {:?}",
+        Compression::from(headers.internal_compression)
+    );
+    println!("  clustered:              {}", headers.clustered == 1);
+    println!(
+        "  zoom levels:            {} - {}",
+        headers.min_zoom, headers.max_zoom
+    );
+    println!(
+        "  bounds:                 {}, {}, {}, {}",
+        headers.min_lon, headers.min_lat, headers.max_lon, headers.max_lat
+    );
+    println!(
+        "  center:                 {}, {} @ {}",
+        headers.center_lon, headers.center_lat, headers.center_zoom
+    );
+    let sections = [
+        (
+            "root directory",
+            headers.root_directory_offset,
+            headers.root_directory_length,
+        ),
+        (
+            "metadata",
+            headers.json_metadata_offset,
+            headers.json_metadata_length,
+        ),
+        (
+            "leaf directories",
+            headers.leaf_directory_offset,
+            headers.leaf_directory_length,
+        ),
+        (
+            "tile data",
+            headers.tile_data_offset,
+            headers.tile_data_length,
+        ),
+    ];
+    for (name, offset, length) in sections {
+        println!(
+            "  {:<23} offset {}, length {}",
+            format!("{}:", name),
+            offset,
+            length
+        );
+    }
+    println!("  addressed tiles:        {}", headers.num_addressed_tiles);
+    println!("  tile entries:           {}", headers.num_tile_entries);
+    println!("  tile contents:          {}", headers.num_tile_contents);
+    if let Some(etag) = &headers.etag {
+        println!("  etag:                   {}", etag);
+    }
+}
+
+fn print_stats(stats: &ArchiveStats) {
+    println!("Tiles");
+    println!("  directory levels:       {}", stats.directory_levels);
+    for (z, count) in &stats.tiles_per_zoom {
+        println!("  {:<23} {}", format!("zoom {}:", z), count);
+    }
+    println!("  addressed tiles:        {}", stats.addressed_tiles);
+    println!("  distinct contents:      {}", stats.tile_contents);
+    println!("  total size:             {} bytes", stats.total_size);
+    println!("  stored size:            {} bytes", stats.stored_size);
+    println!(
+        "  average size:           {:.1} bytes",
+        stats.average_size()
+    );
+    println!(
+        "  deduplication ratio:    {:.2}",
+        stats.deduplication_ratio()
+    );
+}
+
+// Print the header, metadata and tile statistics of a local, S3 or HTTP archive
+pub async fn inspect_archive(path: &str) -> anyhow::Result<()> {
+    let fetcher = create_fetcher().await;
+    let (headers, metadata) = get_metadata(path, &fetcher, None as Option<&InMemoryCache>).await?;
+    let stats = collect_stats(path, &fetcher).await?;
+    print_headers(&headers);
+    println!("Metadata");
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+    print_stats(&stats);
+    Ok(())
+}
+
+// Print a JSON report of checking an archive, failing if the archive is invalid
+pub async fn verify(path: &str) -> anyhow::Result<()> {
+    let fetcher = create_fetcher().await;
+    let report = verify_archive(path, &fetcher).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.valid {
+        anyhow::bail!("{} failed verification", path);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_collect_stats() {
+    use pmtiles_core::fetcher::LocalFetcher;
+    use pmtiles_core::writer::PMTilesWriter;
+
+    let mut writer = PMTilesWriter::new(TileType::Mvt, Compression::None).unwrap();
+    writer.add_tile(0, 0, 0, b"world").unwrap();
+    for x in 0..2 {
+        for y in 0..2 {
+            writer.add_tile(1, x, y, b"ocean").unwrap();
+        }
+    }
+    writer.add_tile(2, 1, 1, b"land").unwrap();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    writer.finish(&mut file.as_file()).unwrap();
+
+    let client = LocalFetcher::new();
+    let stats = collect_stats(file.path().to_str().unwrap(), &client)
+        .await
+        .unwrap();
+    assert_eq!(
+        stats,
+        ArchiveStats {
+            directory_levels: 1,
+            tiles_per_zoom: BTreeMap::from([(0, 1), (1, 4), (2, 1)]),
+            addressed_tiles: 6,
+            tile_contents: 3,
+            total_size: 5 + 4 * 5 + 4,
+            stored_size: 5 + 5 + 4,
+        }
+    );
+    assert_eq!(stats.deduplication_ratio(), 2.);
+    assert_eq!(stats.average_size(), 29. / 6.);
+}
@@ -1,27 +1,103 @@
-use crate::server::serve;
+use crate::extract::{extract_archive, upgrade_archive};
+use crate::inspect::{inspect_archive, verify};
+use crate::mbtiles::convert_mbtiles;
+use crate::server::{init_tracing, serve};
 use anyhow::Error;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long, default_value = "false")]
     serve: bool,
     #[arg(short, long, default_value = "5000")]
     port: u32,
     #[arg(short, long, default_value = "127.0.0.1")]
-    listen_addr: String
+    listen_addr: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert an MBTiles file into a PMTiles v3 archive
+    Convert {
+        /// Path of the MBTiles file to read
+        input: String,
+        /// Path of the PMTiles archive to write
+        output: String,
+    },
+    /// Print the header, metadata and tile statistics of a PMTiles archive
+    Inspect {
+        /// Local, s3:// or http(s):// path of the archive
+        path: String,
+    },
+    /// Extract the tiles within an area into a new PMTiles archive
+    Extract {
+        /// Local, s3:// or http(s):// path of the archive to read
+        input: String,
+        /// Path of the PMTiles archive to write
+        output: String,
+        /// Bounding box to extract as min_lon,min_lat,max_lon,max_lat
+        #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+        bbox: Option<Vec<f64>>,
+        /// GeoJSON file with the polygons to extract
+        #[arg(long, conflicts_with = "bbox", required_unless_present = "bbox")]
+        region: Option<String>,
+        /// Minimum zoom level to extract, defaults to that of the archive
+        #[arg(long)]
+        min_zoom: Option<u8>,
+        /// Maximum zoom level to extract, defaults to that of the archive
+        #[arg(long)]
+        max_zoom: Option<u8>,
+    },
+    /// Check that a PMTiles archive is complete and consistent and print a JSON report
+    Verify {
+        /// Local, s3:// or http(s):// path of the archive
+        path: String,
+    },
+    /// Convert a PMTiles v2 archive into a PMTiles v3 archive
+    Upgrade {
+        /// Local, s3:// or http(s):// path of the v2 archive to read
+        input: String,
+        /// Path of the PMTiles v3 archive to write
+        output: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let args = Args::parse();
-    serve(args.serve, &args.listen_addr, args.port).await
+    init_tracing(args.command.is_some());
+    match args.command {
+        Some(Command::Convert { input, output }) => {
+            tokio::task::spawn_blocking(move || convert_mbtiles(&input, &output)).await??;
+            Ok(())
+        }
+        Some(Command::Inspect { path }) => inspect_archive(&path).await,
+        Some(Command::Verify { path }) => verify(&path).await,
+        Some(Command::Upgrade { input, output }) => upgrade_archive(&input, &output).await,
+        Some(Command::Extract {
+            input,
+            output,
+            bbox,
+            region,
+            min_zoom,
+            max_zoom,
+        }) => extract_archive(&input, &output, bbox, region, min_zoom, max_zoom).await,
+        None => serve(args.serve, &args.listen_addr, args.port).await,
+    }
 }
 
 mod config;
 mod error;
+mod etag;
+mod extract;
 mod font;
+mod inspect;
+mod mbtiles;
 mod routes;
 mod server;
+mod sprite;
 mod style;
+mod tile;
 mod utils;
@@ -0,0 +1,445 @@
+use crate::error::APIError;
+use pmtiles_core::compress::{detect_compression, Compression};
+use pmtiles_core::models::{Headers, RawTile, TileType};
+use pmtiles_core::writer::PMTilesWriter;
+use pmtiles_core::{metadata_etag, tile_etag};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+pub fn open_mbtiles(path: &str) -> anyhow::Result<Connection> {
+    let connection = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    Ok(connection)
+}
+
+// Deepest zoom level a tile id can address
+const MAX_ZOOM: u8 = 26;
+
+// Tile rows of MBTiles are in TMS order with y pointing north. Zoom levels and rows are
+// read from user supplied files, so ones that do not address a tile are rejected.
+pub fn flip_y(z: u8, y: u64) -> anyhow::Result<u64> {
+    if z > MAX_ZOOM {
+        anyhow::bail!(
+            "zoom level {} is deeper than the maximum of {}",
+            z,
+            MAX_ZOOM
+        );
+    }
+    (1_u64 << z)
+        .checked_sub(1)
+        .and_then(|max| max.checked_sub(y))
+        .ok_or_else(|| anyhow::anyhow!("tile row {} is out of range at zoom level {}", y, z))
+}
+
+// Read the metadata table as JSON, merging the keys of the nested `json` value
+pub fn read_metadata(connection: &Connection) -> anyhow::Result<Map<String, Value>> {
+    let mut statement = connection.prepare("SELECT name, value FROM metadata")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut metadata = Map::new();
+    let mut nested = None;
+    for row in rows {
+        let (name, value) = row?;
+        if name == "json" {
+            nested = Some(value);
+        } else {
+            metadata.insert(name, Value::String(value));
+        }
+    }
+    if let Some(nested) = nested {
+        match serde_json::from_str(&nested)? {
+            Value::Object(fields) => metadata.extend(fields),
+            _ => anyhow::bail!("metadata json is not an object"),
+        }
+    }
+    Ok(metadata)
+}
+
+// Parse a comma separated list of numbers such as the bounds and center of the metadata
+pub fn parse_numbers(metadata: &Map<String, Value>, key: &str) -> Option<Vec<f64>> {
+    metadata
+        .get(key)?
+        .as_str()?
+        .split(',')
+        .map(|part| part.trim().parse::<f64>().ok())
+        .collect()
+}
+
+// Describe an MBTiles tileset with the PMTiles headers used for TileJSON
+pub fn headers_from_metadata(metadata: &Map<String, Value>) -> Headers {
+    let zoom = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(Value::as_str)
+            .and_then(|zoom| zoom.parse::<u8>().ok())
+    };
+    let min_zoom = zoom("minzoom").unwrap_or(0);
+    let max_zoom = zoom("maxzoom").unwrap_or(min_zoom);
+    let bounds = parse_numbers(metadata, "bounds")
+        .filter(|bounds| bounds.len() == 4)
+        .unwrap_or(vec![-180., -85.0511287, 180., 85.0511287]);
+    let center = parse_numbers(metadata, "center")
+        .filter(|center| center.len() == 3)
+        .unwrap_or(vec![
+            (bounds[0] + bounds[2]) / 2.,
+            (bounds[1] + bounds[3]) / 2.,
+            min_zoom as f64,
+        ]);
+    let format = metadata
+        .get("format")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    Headers {
+        tile_type: TileType::from_format(format).into(),
+        min_zoom,
+        max_zoom,
+        min_lon: bounds[0],
+        min_lat: bounds[1],
+        max_lon: bounds[2],
+        max_lat: bounds[3],
+        center_lon: center[0],
+        center_lat: center[1],
+        center_zoom: center[2] as u8,
+        ..Default::default()
+    }
+}
+
+// Most read-only connections opened to a single MBTiles file
+const MAX_CONNECTIONS: usize = 4;
+
+// An MBTiles file with its idle connections. Each query holds a permit while it runs,
+// which bounds the number of connections opened to the file.
+struct MBTilesSource {
+    path: String,
+    tile_type: TileType,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+impl MBTilesSource {
+    fn run<T>(&self, query: impl FnOnce(&Connection) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let idle = self
+            .idle
+            .lock()
+            .map_err(|_| anyhow::anyhow!("mbtiles connections unavailable"))?
+            .pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => open_mbtiles(&self.path)?,
+        };
+        let res = query(&connection);
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push(connection);
+        }
+        res
+    }
+}
+
+// ETag of an MBTiles file, which changes when the file is replaced or written to
+fn file_etag(path: &str) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    metadata_etag(&metadata).ok()
+}
+
+// Read-only connections to the MBTiles files being served, opened on first use
+#[derive(Default)]
+pub struct MBTilesPool {
+    sources: Mutex<HashMap<String, Arc<MBTilesSource>>>,
+}
+
+impl MBTilesPool {
+    pub fn new() -> Self {
+        MBTilesPool::default()
+    }
+
+    fn source(&self, path: &str) -> Result<Arc<MBTilesSource>, APIError> {
+        let mut sources = self
+            .sources
+            .lock()
+            .map_err(|_| APIError::Internal("mbtiles connections unavailable".into()))?;
+        if let Some(source) = sources.get(path) {
+            return Ok(source.clone());
+        }
+        let open = || -> anyhow::Result<MBTilesSource> {
+            let connection = open_mbtiles(path)?;
+            let metadata = read_metadata(&connection)?;
+            let format = metadata
+                .get("format")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            Ok(MBTilesSource {
+                path: path.to_string(),
+                tile_type: TileType::from_format(format),
+                idle: Mutex::new(vec![connection]),
+                permits: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            })
+        };
+        let source = Arc::new(open().map_err(|err| {
+            tracing::error!("unable to open {}: {}", path, err);
+            APIError::NotFound(Some("tileset not found".into()))
+        })?);
+        sources.insert(path.to_string(), source.clone());
+        Ok(source)
+    }
+
+    pub fn tile_type(&self, path: &str) -> Result<TileType, APIError> {
+        Ok(self.source(path)?.tile_type)
+    }
+
+    async fn query<T, F>(&self, source: Arc<MBTilesSource>, query: F) -> Result<T, APIError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let permit = source
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|err| APIError::Internal(err.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            let res = source.run(query);
+            drop(permit);
+            res
+        })
+        .await
+        .map_err(|err| APIError::Internal(err.to_string()))?
+        .map_err(|err| {
+            tracing::error!("mbtiles query failed: {}", err);
+            APIError::Internal("unable to read mbtiles".into())
+        })
+    }
+
+    pub async fn get_raw_tile(
+        &self,
+        path: &str,
+        z: u64,
+        x: u64,
+        y: u64,
+    ) -> Result<RawTile, APIError> {
+        if z > MAX_ZOOM as u64 || x >> z != 0 {
+            return Err(APIError::NotFound(None));
+        }
+        let z = z as u8;
+        let row = flip_y(z, y).map_err(|_| APIError::NotFound(None))?;
+        let source = self.source(path)?;
+        let tile_type = source.tile_type;
+        let file = source.path.clone();
+        let (tile, etag) = self
+            .query(source, move |connection| {
+                // Read the ETag first, so that a tile read while the file is being
+                // replaced is not sent with the ETag of the new file
+                let etag = file_etag(&file).and_then(|etag| tile_etag(&etag, z as u64, x, y));
+                let tile = connection
+                    .query_row(
+                        "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                        rusqlite::params![z, x, row],
+                        |row| row.get::<_, Vec<u8>>(0),
+                    )
+                    .optional()?;
+                Ok(tile.map(|tile| (tile, etag)))
+            })
+            .await?
+            .ok_or(APIError::NotFound(None))?;
+        Ok(RawTile {
+            compression: detect_compression(&tile),
+            data: tile.into(),
+            tile_type,
+            etag,
+        })
+    }
+
+    pub async fn get_metadata(&self, path: &str) -> Result<(Headers, Value), APIError> {
+        let metadata = self.query(self.source(path)?, read_metadata).await?;
+        Ok((headers_from_metadata(&metadata), Value::Object(metadata)))
+    }
+}
+
+pub fn convert_mbtiles(input: &str, output: &str) -> anyhow::Result<Headers> {
+    let connection = open_mbtiles(input)?;
+    let metadata = read_metadata(&connection)?;
+    let format = metadata
+        .get("format")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let tile_type = TileType::from_format(format);
+    if tile_type == TileType::Unknown {
+        tracing::warn!("unknown tile format '{}' in {}", format, input);
+    }
+
+    let first_tile: Option<Vec<u8>> = connection
+        .query_row("SELECT tile_data FROM tiles LIMIT 1", [], |row| row.get(0))
+        .ok();
+    let tile_compression = first_tile
+        .as_deref()
+        .map(detect_compression)
+        .unwrap_or(Compression::None);
+
+    let mut writer = PMTilesWriter::new(tile_type, tile_compression)?;
+    if let Some(bounds) = parse_numbers(&metadata, "bounds").filter(|b| b.len() == 4) {
+        writer.set_bounds(bounds[0], bounds[1], bounds[2], bounds[3]);
+    }
+    if let Some(center) = parse_numbers(&metadata, "center").filter(|c| c.len() == 3) {
+        writer.set_center(center[0], center[1], center[2] as u8);
+    }
+    writer.set_metadata(Value::Object(metadata));
+
+    let mut statement =
+        connection.prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let z: u8 = row.get(0)?;
+        let x: u64 = row.get(1)?;
+        let y: u64 = row.get(2)?;
+        let data: Vec<u8> = row.get(3)?;
+        let y = flip_y(z, y)
+            .map_err(|err| anyhow::anyhow!("invalid tile {}/{}/{}: {}", z, x, y, err))?;
+        writer.add_tile(z, x, y, &data)?;
+        if writer.num_tiles() % 100_000 == 0 {
+            tracing::info!("read {} tiles", writer.num_tiles());
+        }
+    }
+
+    let mut out = BufWriter::new(File::create(output)?);
+    let headers = writer.finish(&mut out)?;
+    tracing::info!(
+        "wrote {} tiles ({} unique) to {}",
+        headers.num_addressed_tiles,
+        headers.num_tile_contents,
+        output
+    );
+    Ok(headers)
+}
+
+#[cfg(test)]
+pub fn create_test_mbtiles(path: &std::path::Path) {
+    let connection = Connection::open(path).unwrap();
+    connection
+        .execute_batch(
+            "CREATE TABLE metadata (name text, value text);
+            CREATE TABLE tiles (zoom_level integer, tile_column integer, tile_row integer, tile_data blob);
+            INSERT INTO metadata VALUES ('name', 'test'), ('format', 'png'), ('minzoom', '0'),
+                ('maxzoom', '1'), ('bounds', '-10,-20,30,40'), ('center', '10,10,1'),
+                ('json', '{\"attribution\": \"test data\"}');",
+        )
+        .unwrap();
+    let tiles: [(u8, u64, u64, &[u8]); 3] = [
+        (0, 0, 0, b"zero"),
+        (1, 0, 1, b"north-west"),
+        (1, 1, 0, b"south-east"),
+    ];
+    for (z, x, y, data) in tiles {
+        connection
+            .execute(
+                "INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![z, x, y, data],
+            )
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_convert_mbtiles() {
+    use pmtiles_core::cache::InMemoryCache;
+    use pmtiles_core::fetcher::LocalFetcher;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("test.mbtiles");
+    let output = dir.path().join("test.pmtiles");
+    create_test_mbtiles(&input);
+
+    let headers = convert_mbtiles(input.to_str().unwrap(), output.to_str().unwrap()).unwrap();
+    assert_eq!(headers.num_addressed_tiles, 3);
+    assert_eq!(TileType::from(headers.tile_type), TileType::Png);
+    assert_eq!(headers.max_lat, 40.);
+    assert_eq!(headers.center_zoom, 1);
+
+    let client = LocalFetcher::new();
+    let path = output.to_str().unwrap();
+    let tile = pmtiles_core::get_tile(1, 0, 0, path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"north-west"[..]);
+    let tile = pmtiles_core::get_tile(1, 1, 1, path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(tile, &b"south-east"[..]);
+
+    let (_, metadata) = pmtiles_core::get_metadata(path, &client, None as Option<&InMemoryCache>)
+        .await
+        .unwrap();
+    assert_eq!(metadata["name"], "test");
+    assert_eq!(metadata["maxzoom"], "1");
+    assert_eq!(metadata["attribution"], "test data");
+
+    // Rows and zoom levels that do not address a tile are rejected
+    for (z, y) in [(1, 2), (64, 0)] {
+        let input = dir.path().join(format!("invalid-{}.mbtiles", z));
+        create_test_mbtiles(&input);
+        Connection::open(&input)
+            .unwrap()
+            .execute(
+                "INSERT INTO tiles VALUES (?1, 0, ?2, x'00')",
+                rusqlite::params![z, y],
+            )
+            .unwrap();
+        assert!(convert_mbtiles(input.to_str().unwrap(), output.to_str().unwrap()).is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_serve_mbtiles() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("test.mbtiles");
+    create_test_mbtiles(&input);
+    let path = input.to_str().unwrap();
+
+    let pool = MBTilesPool::new();
+    let tile = pool.get_raw_tile(path, 1, 0, 0).await.unwrap();
+    assert_eq!(tile.data, &b"north-west"[..]);
+    assert_eq!(tile.compression, Compression::None);
+    assert_eq!(tile.tile_type, TileType::Png);
+    let north_west_etag = tile.etag.unwrap();
+    let tile = pool.get_raw_tile(path, 0, 0, 0).await.unwrap();
+    assert_eq!(tile.data, &b"zero"[..]);
+    // Tile ETags are derived from the file and differ between tiles
+    let file_etag = file_etag(path).unwrap();
+    assert!(north_west_etag.starts_with(file_etag.trim_end_matches('"')));
+    assert_ne!(tile.etag.unwrap(), north_west_etag);
+    assert!(matches!(
+        pool.get_raw_tile(path, 1, 1, 0).await,
+        Err(APIError::NotFound(_))
+    ));
+    assert!(matches!(
+        pool.get_raw_tile(path, 1, 2, 0).await,
+        Err(APIError::NotFound(_))
+    ));
+
+    let (headers, metadata) = pool.get_metadata(path).await.unwrap();
+    assert_eq!(metadata["name"], "test");
+    assert_eq!(TileType::from(headers.tile_type), TileType::Png);
+    assert_eq!(headers.max_zoom, 1);
+    assert_eq!(headers.min_lon, -10.);
+    assert_eq!(headers.center_zoom, 1);
+
+    // Concurrent reads share a bounded number of connections
+    let pool = Arc::new(pool);
+    let mut reads = tokio::task::JoinSet::new();
+    for _ in 0..MAX_CONNECTIONS * 2 {
+        let (pool, path) = (pool.clone(), path.to_string());
+        reads.spawn(async move { pool.get_raw_tile(&path, 1, 0, 0).await });
+    }
+    while let Some(tile) = reads.join_next().await {
+        assert_eq!(tile.unwrap().unwrap().data, &b"north-west"[..]);
+    }
+    let idle = pool.source(path).unwrap().idle.lock().unwrap().len();
+    assert!((1..=MAX_CONNECTIONS).contains(&idle));
+}
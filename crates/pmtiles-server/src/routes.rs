@@ -1,17 +1,22 @@
-use crate::config::{prefix_with_home, ServerConfig};
+use crate::config::{prefix_with_home, Route, ServerConfig, TilesetSource};
 use crate::error::APIError;
+use crate::etag::{content_etag, encoded_etag, etag_response, is_not_modified, not_modified};
 use crate::font::fetch_fonts;
-use crate::server::AppState;
+use crate::server::{AppCache, AppFetcher, AppState};
+use crate::sprite::{fetch_generated_sprite, fetch_sprite, parse_sprite_name, sprite_content_type};
 use crate::style::{Style, TileSource};
+use crate::tile::{TileRequest, TileScheme};
+use crate::utils::accepts_encoding;
 use axum::body::Body;
 use axum::extract::{Path, State};
-use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
 use axum::{routing::get, Router};
-use hyper::StatusCode;
-use pmtiles_core::cache::InMemoryCache;
-use pmtiles_core::fetcher::{Fetcher, S3OrLocalFetcher};
+use pmtiles_core::compress::decompress;
+use pmtiles_core::fetcher::Fetcher;
+use pmtiles_core::models::{RawTile, TileType};
 use pmtiles_core::{self, get_metadata};
+use serde::Serialize;
 use std::borrow::Borrow;
 
 async fn fetch_style<F: Fetcher>(
@@ -23,7 +28,7 @@ async fn fetch_style<F: Fetcher>(
         .styles
         .get(style_id)
         .ok_or_else(|| APIError::NotFound(Some("style not found".into())))?;
-    let style_path = config.get_style_path(&style_cfg).map_err(|err| {
+    let style_path = config.get_style_path(style_cfg).map_err(|err| {
         tracing::error!("unable to get style {} path: {}", style_id, err);
         APIError::Internal("unable to get style path".into())
     })?;
@@ -35,106 +40,203 @@ async fn fetch_style<F: Fetcher>(
         tracing::error!("unable to parse style {}: {}", style_id, err);
         APIError::Internal("error parsing style".into())
     })?;
-    let resolved = style.resolve(&config);
+    let resolved = style.resolve(config);
     Ok(resolved)
 }
 
+// Add the configured Cache-Control header to successful and not modified responses
+fn with_cache_control(
+    response: Result<Response, APIError>,
+    cache_control: Option<String>,
+) -> Result<Response, APIError> {
+    let mut response = response?;
+    let status = response.status();
+    if let Some(cache_control) = cache_control {
+        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            let value = HeaderValue::from_str(&cache_control).map_err(|err| {
+                tracing::error!("invalid cache control {}: {}", cache_control, err);
+                APIError::Internal("invalid cache control".into())
+            })?;
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+    }
+    Ok(response)
+}
+
+fn json_response<T: Serialize>(headers: &HeaderMap, value: &T) -> Result<Response, APIError> {
+    let data = serde_json::to_vec(value).map_err(|err| {
+        tracing::error!("{}", err);
+        APIError::Internal("unable to serialize response".into())
+    })?;
+    etag_response(headers, "application/json", data.into())
+}
+
 async fn get_style(
     State(state): State<AppState>,
     Path(style_id): Path<String>,
+    headers: HeaderMap,
 ) -> Result<Response, APIError> {
-    let fetcher: &S3OrLocalFetcher = state.fetcher.borrow();
+    let fetcher: &AppFetcher = state.fetcher.borrow();
     let resolved = fetch_style(&state.config, fetcher, &style_id).await?;
-    Ok((StatusCode::OK, Json(resolved)).into_response())
+    with_cache_control(
+        json_response(&headers, &resolved),
+        state.config.get_cache_control(Route::Styles, None),
+    )
 }
 
 async fn get_tilejson(
     State(state): State<AppState>,
     Path(tileset): Path<String>,
+    request_headers: HeaderMap,
 ) -> Result<Response, APIError> {
     let tileset = tileset.replace(".json", "");
-    let path = &state.config.get_tileset_path(&tileset).map_err(|err| {
+    let source = state.config.get_tileset_source(&tileset).map_err(|err| {
         tracing::error!("unable to get tileset: {}", err);
         APIError::NotFound(Some("tileset not found".into()))
     })?;
-    let fetcher: &S3OrLocalFetcher = state.fetcher.borrow();
-    let cache: &InMemoryCache = state.cache.borrow();
-    let (headers, metadata) = get_metadata(path, fetcher, Some(cache)).await?;
+    let (headers, metadata) = match source {
+        TilesetSource::PMTiles(path) => {
+            let fetcher: &AppFetcher = state.fetcher.borrow();
+            let cache: &AppCache = state.cache.borrow();
+            get_metadata(&path, fetcher, Some(cache)).await?
+        }
+        TilesetSource::MBTiles(path) => state.mbtiles.get_metadata(&path).await?,
+    };
     let tilejson =
         TileSource::try_from_headers_and_metadata(&tileset, &headers, &metadata, &state.config)?;
-    Ok((StatusCode::OK, Json(tilejson)).into_response())
+    with_cache_control(
+        json_response(&request_headers, &tilejson),
+        state
+            .config
+            .get_cache_control(Route::TileJson, Some(&tileset)),
+    )
 }
 
-fn parse_tile(tile_param: &str) -> Result<(u64, u64, u64), APIError> {
-    let parts = tile_param
-        .replace(".pbf", "")
-        .split("/")
-        .map(|p| p.parse::<u64>())
-        .collect::<Vec<_>>();
-    if parts.len() != 3 {
-        return Err(APIError::Validation(
-            "invalid tile parameters: provide tiles as /z/x/y.pbf".to_string(),
-        ));
+// Send the tile as stored if the client accepts its compression and decompress it otherwise
+fn tile_response(tile: RawTile, headers: &HeaderMap) -> Result<Response, APIError> {
+    let etag = tile
+        .etag
+        .clone()
+        .unwrap_or_else(|| content_etag(&tile.data));
+    let encoding = tile
+        .compression
+        .content_encoding()
+        .filter(|encoding| accepts_encoding(headers, encoding));
+    let etag = match encoding {
+        Some(encoding) => encoded_etag(&etag, encoding),
+        None => etag,
+    };
+    if is_not_modified(headers, &etag) {
+        return not_modified(&etag);
+    }
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, tile.tile_type.content_type())
+        .header(header::ETAG, etag);
+    let data = match encoding {
+        Some(encoding) => {
+            // The compression layer skips encoded responses, so vary is set here
+            response = response
+                .header(header::CONTENT_ENCODING, encoding)
+                .header(header::VARY, "Accept-Encoding");
+            tile.data
+        }
+        None => decompress(tile.data, tile.compression)?,
     };
-    let z = parts[0]
-        .as_ref()
-        .map_err(|_| APIError::Validation("invalid tile specified".to_string()))?;
-    let x = parts[1]
-        .as_ref()
-        .map_err(|_| APIError::Validation("invalid tile specified".to_string()))?;
-    let y = parts[2]
-        .as_ref()
-        .map_err(|_| APIError::Validation("invalid tile specified".to_string()))?;
-    Ok((*z, *x, *y))
+    response.body(Body::from(data)).map_err(|err| {
+        tracing::error!("{}", err);
+        APIError::Internal("invalid tile data".into())
+    })
 }
 
-async fn get_tile(
-    State(state): State<AppState>,
-    Path((tileset, tile)): Path<(String, String)>,
+// Tile type of a tileset, read from the cached headers of PMTiles archives
+async fn tileset_tile_type(state: &AppState, source: &TilesetSource) -> Result<TileType, APIError> {
+    match source {
+        TilesetSource::PMTiles(path) => {
+            let fetcher: &AppFetcher = state.fetcher.borrow();
+            let cache: &AppCache = state.cache.borrow();
+            Ok(pmtiles_core::get_tile_type(path, fetcher, Some(cache)).await?)
+        }
+        TilesetSource::MBTiles(path) => state.mbtiles.tile_type(path),
+    }
+}
+
+async fn serve_tile(
+    state: AppState,
+    tileset: String,
+    tile: TileRequest,
+    headers: HeaderMap,
 ) -> Result<Response, APIError> {
-    let path = &state.config.get_tileset_path(&tileset).map_err(|err| {
+    let source = state.config.get_tileset_source(&tileset).map_err(|err| {
         tracing::error!("unable to get tileset: {}", err);
         APIError::NotFound(Some("tileset not found".into()))
     })?;
-    tracing::debug!("Fetching tiles from path {}", path);
-    let (z, x, y) = parse_tile(&tile)?;
-    let fetcher: &S3OrLocalFetcher = state.fetcher.borrow();
-    let cache: &InMemoryCache = state.cache.borrow();
-    let tile_res = pmtiles_core::get_tile(z, x, y, &path, fetcher, Some(cache)).await;
+    let tile_type = tileset_tile_type(&state, &source).await?;
+    tile.check_extension(tile_type)?;
+    tile.check_retina(tile_type)?;
+    let TileRequest { z, x, y, .. } = tile;
+    let tile_res = match source {
+        TilesetSource::PMTiles(path) => {
+            tracing::debug!("Fetching tiles from path {}", path);
+            let fetcher: &AppFetcher = state.fetcher.borrow();
+            let cache: &AppCache = state.cache.borrow();
+            pmtiles_core::get_raw_tile(z, x, y, &path, fetcher, Some(cache))
+                .await
+                .map_err(Into::into)
+        }
+        TilesetSource::MBTiles(path) => {
+            tracing::debug!("Fetching tiles from path {}", path);
+            state.mbtiles.get_raw_tile(&path, z, x, y).await
+        }
+    };
     match tile_res {
-        Ok(tile_data) => Response::builder()
-            .header("Content-Type", "application/octet-stream")
-            .body(Body::from(tile_data))
-            .map_err(|err| {
-                tracing::error!("{}", err);
-                APIError::Internal("invalid tile data".into())
-            }),
+        Ok(tile) => with_cache_control(
+            tile_response(tile, &headers),
+            state.config.get_cache_control(Route::Tiles, Some(&tileset)),
+        ),
         Err(err) => {
             tracing::error!("{}", err);
-            return Err(err.into());
+            Err(err)
         }
     }
 }
 
+async fn get_tile(
+    State(state): State<AppState>,
+    Path((tileset, z, x, y)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, APIError> {
+    let tile = TileRequest::parse(&z, &x, &y, TileScheme::Xyz)?;
+    serve_tile(state, tileset, tile, headers).await
+}
+
+async fn get_tms_tile(
+    State(state): State<AppState>,
+    Path((tileset, z, x, y)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, APIError> {
+    let tile = TileRequest::parse(&z, &x, &y, TileScheme::Tms)?;
+    serve_tile(state, tileset, tile, headers).await
+}
+
 async fn get_fontstack(
     State(state): State<AppState>,
     Path((fontstack, range)): Path<(String, String)>,
+    headers: HeaderMap,
 ) -> Result<Response, APIError> {
     let range = range.replace(".pbf", "");
     let font_paths_resolved = fontstack
         .split(",")
         .map(|f| state.config.get_font_path(f.trim(), &range))
         .collect::<Result<Vec<_>, anyhow::Error>>()?;
-    let fetcher: &S3OrLocalFetcher = state.fetcher.borrow();
-    let cache: &InMemoryCache = state.cache.borrow();
+    let fetcher: &AppFetcher = state.fetcher.borrow();
+    let cache: &AppCache = state.cache.borrow();
     let result = fetch_fonts(font_paths_resolved, fetcher, Some(cache)).await;
     match result {
-        Ok(fonts_pbf) => Response::builder()
-            .body(Body::from(fonts_pbf))
-            .map_err(|err| {
-                tracing::error!("{}", err);
-                APIError::Internal("invalid font data".into())
-            }),
+        Ok(fonts_pbf) => with_cache_control(
+            etag_response(&headers, "application/x-protobuf", fonts_pbf),
+            state.config.get_cache_control(Route::Fonts, None),
+        ),
         Err(err) => {
             tracing::error!("{}", err);
             Err(err)
@@ -145,10 +247,45 @@ async fn get_fontstack(
 async fn get_sprite(
     State(state): State<AppState>,
     Path(sprite): Path<String>,
+    headers: HeaderMap,
 ) -> Result<Response, APIError> {
-    let fetcher: &S3OrLocalFetcher = state.fetcher.borrow();
-    let cache: &InMemoryCache = state.cache.borrow();
-    todo!()
+    let content_type = sprite_content_type(&sprite)
+        .ok_or_else(|| APIError::NotFound(Some("sprite must be a .json or .png file".into())))?;
+    let sprite_path = state.config.get_sprite_path(&sprite)?;
+    let fetcher: &AppFetcher = state.fetcher.borrow();
+    let cache: &AppCache = state.cache.borrow();
+    let result = match fetch_sprite(&sprite_path, fetcher, Some(cache)).await {
+        // Fall back to generating the sprite from icons when no sprite file exists
+        Err(APIError::NotFound(err)) => {
+            let (name, pixel_ratio, extension) = parse_sprite_name(&sprite)
+                .ok_or_else(|| APIError::NotFound(Some("invalid sprite name".into())))?;
+            match state.config.get_icons_path(name)? {
+                Some(icons_path) => {
+                    fetch_generated_sprite(
+                        &icons_path,
+                        pixel_ratio,
+                        extension,
+                        fetcher,
+                        Some(cache),
+                        &state.sprites,
+                    )
+                    .await
+                }
+                None => Err(APIError::NotFound(err)),
+            }
+        }
+        result => result,
+    };
+    match result {
+        Ok(sprite_data) => with_cache_control(
+            etag_response(&headers, content_type, sprite_data),
+            state.config.get_cache_control(Route::Sprites, None),
+        ),
+        Err(err) => {
+            tracing::error!("{}", err);
+            Err(err)
+        }
+    }
 }
 
 pub fn create_router(state: AppState) -> Router {
@@ -164,7 +301,8 @@ pub fn create_router(state: AppState) -> Router {
     );
     prefix_with_home(&mut get_tilejson_path, &state.config, true, false);
 
-    let get_tile_path = format!("{}/*tile", get_tilejson_path);
+    let get_tile_path = format!("{}/:z/:x/:y", get_tilejson_path);
+    let get_tms_tile_path = format!("{}/tms/:z/:x/:y", get_tilejson_path);
 
     let mut get_style_path = format!(
         "/{}/:style_id",
@@ -184,14 +322,16 @@ pub fn create_router(state: AppState) -> Router {
         .route(&get_style_path, get(get_style))
         .route(&get_style_json_path, get(get_style))
         .route(&get_tilejson_path, get(get_tilejson))
-        .route(&get_tile_path, get(get_tile));
+        .route(&get_tile_path, get(get_tile))
+        .route(&get_tms_tile_path, get(get_tms_tile));
 
     tracing::debug!(
-        "Exposing paths: \nGET {} \nGET {} \nGET {} \nGET {}",
+        "Exposing paths: \nGET {} \nGET {} \nGET {} \nGET {} \nGET {}",
         get_style_path,
         get_style_json_path,
         get_tilejson_path,
-        get_tile_path
+        get_tile_path,
+        get_tms_tile_path
     );
 
     if let Some(fonts_path) = &state.config.options.paths.fonts {
@@ -209,3 +349,86 @@ pub fn create_router(state: AppState) -> Router {
 
     router.with_state(state)
 }
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_get_tile_at_2x() {
+    use crate::mbtiles::MBTilesPool;
+    use crate::sprite::SpriteBuilds;
+    use aws_sdk_s3 as s3;
+    use axum::body::to_bytes;
+    use axum::http::Request;
+    use pmtiles_core::cache::InMemoryCache;
+    use pmtiles_core::compress::Compression;
+    use pmtiles_core::fetcher::{CoalescingFetcher, CombinedFetcher};
+    use pmtiles_core::models::TileType;
+    use pmtiles_core::writer::PMTilesWriter;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("data")).unwrap();
+    for (name, tile_type, tile) in [
+        ("raster", TileType::Png, &b"png"[..]),
+        ("vector", TileType::Mvt, &b"mvt"[..]),
+    ] {
+        let mut writer = PMTilesWriter::new(tile_type, Compression::None).unwrap();
+        writer.add_tile(1, 1, 1, tile).unwrap();
+        let path = dir.path().join("data").join(format!("{}.pmtiles", name));
+        writer
+            .finish(&mut std::fs::File::create(path).unwrap())
+            .unwrap();
+    }
+    let config: ServerConfig = serde_json::from_value(serde_json::json!({
+        "options": {
+            "paths": { "root": dir.path(), "pmtiles": "data" },
+            "domains": ["http://localhost"]
+        },
+        "styles": {},
+        "data": {
+            "raster": { "pmtiles": "raster.pmtiles" },
+            "vector": { "pmtiles": "vector.pmtiles" }
+        }
+    }))
+    .unwrap();
+    let s3_config = s3::Config::builder()
+        .behavior_version(s3::config::BehaviorVersion::latest())
+        .region(s3::config::Region::new("us-east-1"))
+        .build();
+    let router = create_router(AppState {
+        fetcher: Arc::new(CoalescingFetcher::new(CombinedFetcher::new(
+            s3::Client::from_conf(s3_config),
+        ))),
+        cache: Arc::new(InMemoryCache::new()),
+        config: Arc::new(config),
+        mbtiles: Arc::new(MBTilesPool::new()),
+        sprites: Arc::new(SpriteBuilds::new()),
+    });
+    let get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        router.clone().oneshot(request)
+    };
+
+    // @2x is an alias of the tile stored in a raster archive
+    for uri in ["/data/raster/1/1/1.png", "/data/raster/1/1/1@2x.png"] {
+        let res = get(uri).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{uri}");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, &b"png"[..], "{uri}");
+    }
+    let res = get("/data/vector/1/1/1@2x.pbf").await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    // The alias is not advertised, TileJSON points at the stored tiles
+    let res = get("/data/raster.json").await.unwrap();
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let tilejson: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(tilejson["tiles"][0].as_str().unwrap().ends_with("/{y}.png"));
+
+    // Extensions must match the tile type of the tileset
+    for uri in ["/data/raster/1/1/1.pbf", "/data/vector/1/1/1.foo"] {
+        let res = get(uri).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+    let res = get("/data/vector/1/1/1.mvt").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
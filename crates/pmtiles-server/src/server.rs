@@ -1,30 +1,48 @@
 use crate::config::ServerConfig;
 use crate::error::APIError;
+use crate::mbtiles::MBTilesPool;
 use crate::routes::create_router;
+use crate::sprite::SpriteBuilds;
 use anyhow::Error;
 use aws_sdk_s3 as s3;
 use axum::body::Body;
 use axum::response::Response;
-use pmtiles_core::cache::InMemoryCache;
-use pmtiles_core::fetcher::{Fetcher, S3OrLocalFetcher};
+use pmtiles_core::cache::{Cache, DiskCache, InMemoryCache, LruCache, TieredCache, TileCache};
+use pmtiles_core::fetcher::{CoalescingFetcher, CombinedFetcher, Fetcher, MmapFetcher};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tower_http::trace;
 use tower_http::{compression::CompressionLayer, cors::CorsLayer, trace::TraceLayer};
 use tracing::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
 
 // TODO:
-//       - add endpoints fetching sprites
 //       - put lambda specific stuff in server behind a feature flag
 //       - test as mapbox style lambda
 
+const DEFAULT_CACHE_SIZE_MB: usize = 256;
+const DEFAULT_TILE_CACHE_SIZE_MB: usize = 256;
+const DEFAULT_DISK_CACHE_SIZE_MB: usize = 1024;
+
+pub type AppCache = dyn Cache + Send + Sync;
+// Concurrent tile requests share the reads of headers, directories and tiles they have in common
+pub type AppFetcher = CoalescingFetcher<CombinedFetcher>;
+
 #[derive(Clone)]
 pub struct AppState {
-    pub fetcher: Arc<S3OrLocalFetcher>,
-    pub cache: Arc<InMemoryCache>,
+    pub fetcher: Arc<AppFetcher>,
+    pub cache: Arc<AppCache>,
     pub config: Arc<ServerConfig>,
+    pub mbtiles: Arc<MBTilesPool>,
+    pub sprites: Arc<SpriteBuilds>,
+}
+
+// Create a fetcher for local, S3 and HTTP paths, with AWS settings read from the environment
+pub async fn create_fetcher() -> CombinedFetcher {
+    let config = aws_config::from_env().load().await;
+    CombinedFetcher::new(s3::Client::new(&config))
 }
 
 pub async fn get_config<T: Fetcher>(client: &T, path: &str) -> Result<ServerConfig, APIError> {
@@ -37,6 +55,84 @@ pub async fn get_config<T: Fetcher>(client: &T, path: &str) -> Result<ServerConf
     Ok(cfg)
 }
 
+fn create_tile_cache(config: &ServerConfig) -> Option<TileCache> {
+    let tile_cache = config.options.tile_cache.as_ref()?;
+    let max_size_mb = tile_cache.max_size_mb.unwrap_or(DEFAULT_TILE_CACHE_SIZE_MB);
+    tracing::info!("Using a tile cache of {} MB", max_size_mb);
+    if let Some(pin_max_zoom) = tile_cache.pin_max_zoom {
+        tracing::info!("Keeping tiles up to zoom level {} cached", pin_max_zoom);
+    }
+    Some(TileCache::new(
+        max_size_mb * 1024 * 1024,
+        tile_cache.pin_max_zoom,
+    ))
+}
+
+// Put the disk cache behind the in-memory cache, if one is configured
+fn with_disk_cache<C: Cache + Send + Sync + 'static>(
+    config: &ServerConfig,
+    cache: C,
+) -> Result<Arc<AppCache>, Error> {
+    let Some(disk_cache) = &config.options.disk_cache else {
+        return Ok(Arc::new(cache));
+    };
+    let max_size_mb = disk_cache.max_size_mb.unwrap_or(DEFAULT_DISK_CACHE_SIZE_MB);
+    tracing::info!(
+        "Using a disk cache of {} MB in {}",
+        max_size_mb,
+        disk_cache.path
+    );
+    let disk = DiskCache::new(&disk_cache.path, max_size_mb as u64 * 1024 * 1024)?;
+    // Tiles are only written to the disk when they are cached in memory as well
+    let disk = match config.options.tile_cache {
+        Some(_) => disk.with_tiles(),
+        None => disk,
+    };
+    Ok(Arc::new(TieredCache::new(cache, disk)))
+}
+
+pub fn create_cache(config: &ServerConfig) -> Result<Arc<AppCache>, Error> {
+    let tiles = create_tile_cache(config);
+    match &config.options.cache {
+        Some(cache) => {
+            let max_size_mb = cache.max_size_mb.unwrap_or(DEFAULT_CACHE_SIZE_MB);
+            let ttl = cache.ttl_seconds.map(Duration::from_secs);
+            tracing::info!("Using an LRU cache of {} MB", max_size_mb);
+            let cache = LruCache::new(max_size_mb * 1024 * 1024, ttl);
+            let cache = match tiles {
+                Some(tiles) => cache.with_tiles(tiles),
+                None => cache,
+            };
+            with_disk_cache(config, cache)
+        }
+        None => {
+            let cache = InMemoryCache::new();
+            let cache = match tiles {
+                Some(tiles) => cache.with_tiles(tiles),
+                None => cache,
+            };
+            with_disk_cache(config, cache)
+        }
+    }
+}
+
+// Logs go to stderr when running a subcommand, keeping its output on stdout parseable
+pub fn init_tracing(to_stderr: bool) {
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let lyr = tracing_subscriber::fmt::Layer::default()
+        .with_writer(writer)
+        .with_file(true)
+        .with_line_number(true);
+    Registry::default()
+        .with(lyr)
+        .with(EnvFilter::from("info"))
+        .init();
+}
+
 pub async fn serve(serve: bool, listen_addr: &str, port: u32) -> Result<(), Error> {
     // Trace every request
     let trace_layer = TraceLayer::new_for_http()
@@ -53,13 +149,6 @@ pub async fn serve(serve: bool, listen_addr: &str, port: u32) -> Result<(), Erro
             },
         );
 
-    let lyr = tracing_subscriber::fmt::Layer::default()
-        .with_file(true)
-        .with_line_number(true);
-    Registry::default()
-        .with(lyr)
-        .with(EnvFilter::from("info"))
-        .init();
     tracing::info!("Starting server");
 
     // Set up CORS
@@ -68,21 +157,25 @@ pub async fn serve(serve: bool, listen_addr: &str, port: u32) -> Result<(), Erro
         .allow_methods(tower_http::cors::Any)
         .allow_origin(tower_http::cors::Any);
 
-    let config = aws_config::from_env().load().await;
-
     tracing::info!("Setting up state");
 
-    // Create an S3 client and fetcher
-    let client = s3::Client::new(&config);
-    let fetcher = S3OrLocalFetcher::new(client);
+    let fetcher = create_fetcher().await;
     let default_path = "./config.json";
     let cfg_path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| default_path.into());
     let config = get_config(&fetcher, &cfg_path).await?;
+    let fetcher = if config.options.mmap.unwrap_or(false) {
+        tracing::info!("Reading local archives through memory mappings");
+        fetcher.with_mmap(MmapFetcher::new())
+    } else {
+        fetcher
+    };
 
     let state = AppState {
-        fetcher: Arc::new(fetcher),
-        cache: Arc::new(InMemoryCache::new()),
+        fetcher: Arc::new(CoalescingFetcher::new(fetcher)),
+        cache: create_cache(&config)?,
         config: Arc::new(config),
+        mbtiles: Arc::new(MBTilesPool::new()),
+        sprites: Arc::new(SpriteBuilds::new()),
     };
 
     let app = create_router(state)
@@ -0,0 +1,458 @@
+use axum::body::Bytes;
+use pmtiles_core::{cache::Cache, fetcher::Fetcher};
+use resvg::tiny_skia::{FilterQuality, Pixmap, PixmapPaint, Transform};
+use resvg::usvg;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::error::APIError;
+
+const MAX_PIXEL_RATIO: u32 = 4;
+
+#[derive(Serialize)]
+struct SpriteIcon {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    #[serde(rename = "pixelRatio")]
+    pixel_ratio: u32,
+}
+
+// Source files of a single icon: an SVG or a 1x and/or 2x PNG
+#[derive(Default)]
+struct IconSource {
+    svg: Option<String>,
+    png: Option<String>,
+    png_2x: Option<String>,
+}
+
+// Source file of an icon read for a sprite, along with how to render it
+struct IconData {
+    name: String,
+    path: String,
+    data: Bytes,
+    is_svg: bool,
+    scale: f32,
+}
+
+struct Icon {
+    name: String,
+    pixmap: Pixmap,
+}
+
+type SharedSprite = Arc<tokio::sync::Mutex<Option<(Bytes, Bytes)>>>;
+
+// Sprites being generated, so that concurrent requests for the same sprite build it once.
+// The first caller builds the sprite while holding the lock of its entry, and callers
+// waiting on the lock reuse its result.
+#[derive(Default)]
+pub struct SpriteBuilds {
+    building: Mutex<HashMap<String, SharedSprite>>,
+}
+
+impl SpriteBuilds {
+    pub fn new() -> Self {
+        SpriteBuilds::default()
+    }
+
+    fn entry(&self, key: &str) -> SharedSprite {
+        let mut building = self.building.lock().unwrap();
+        building.entry(key.to_string()).or_default().clone()
+    }
+
+    fn remove(&self, key: &str) {
+        self.building.lock().unwrap().remove(key);
+    }
+}
+
+// Resolve the content type of a sprite file, e.g. sprite.json or sprite@2x.png
+pub fn sprite_content_type(sprite: &str) -> Option<&'static str> {
+    if sprite.split('/').any(|part| part == "..") {
+        return None;
+    }
+    if sprite.ends_with(".json") {
+        Some("application/json")
+    } else if sprite.ends_with(".png") {
+        Some("image/png")
+    } else {
+        None
+    }
+}
+
+// Split a sprite file name such as basic@2x.png into its name, pixel ratio and extension
+pub fn parse_sprite_name(sprite: &str) -> Option<(&str, u32, &str)> {
+    let (stem, extension) = sprite.rsplit_once('.')?;
+    match stem.rsplit_once('@') {
+        Some((name, ratio)) => {
+            let ratio = ratio.strip_suffix('x')?.parse::<u32>().ok()?;
+            if ratio == 0 || ratio > MAX_PIXEL_RATIO {
+                return None;
+            }
+            Some((name, ratio, extension))
+        }
+        None => Some((stem, 1, extension)),
+    }
+}
+
+pub async fn fetch_sprite<F: Fetcher, C: Cache + ?Sized>(
+    path: &str,
+    client: &F,
+    cache: Option<&C>,
+) -> Result<Bytes, APIError> {
+    let cache_hit = {
+        match &cache {
+            Some(cache) => cache.get(path),
+            None => None,
+        }
+    };
+    let sprite_data = match cache_hit {
+        Some(cached) => {
+            tracing::debug!("cache hit for key {}", path);
+            cached
+        }
+        None => {
+            let (data, _) = client.get_data(path).await.map_err(|err| {
+                tracing::error!("{}", err);
+                err
+            })?;
+            if let Some(cache) = cache {
+                let res = cache.set(path, data.clone());
+                if let Err(err) = res {
+                    tracing::warn!("failed to cache key {} with {}", path, err);
+                } else {
+                    tracing::debug!("cached key {}", path);
+                }
+            };
+            data
+        }
+    };
+
+    Ok(sprite_data)
+}
+
+fn render_svg(data: &[u8], scale: f32) -> anyhow::Result<Pixmap> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+    let size = tree.size();
+    let width = (size.width() * scale).ceil() as u32;
+    let height = (size.height() * scale).ceil() as u32;
+    let mut pixmap =
+        Pixmap::new(width, height).ok_or_else(|| anyhow::anyhow!("invalid icon size"))?;
+    resvg::render(
+        &tree,
+        Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    Ok(pixmap)
+}
+
+fn render_png(data: &[u8], scale: f32) -> anyhow::Result<Pixmap> {
+    let source = Pixmap::decode_png(data)?;
+    if scale == 1.0 {
+        return Ok(source);
+    }
+    let width = (source.width() as f32 * scale).round() as u32;
+    let height = (source.height() as f32 * scale).round() as u32;
+    let mut pixmap =
+        Pixmap::new(width, height).ok_or_else(|| anyhow::anyhow!("invalid icon size"))?;
+    let paint = PixmapPaint {
+        quality: FilterQuality::Bicubic,
+        ..Default::default()
+    };
+    pixmap.draw_pixmap(
+        0,
+        0,
+        source.as_ref(),
+        &paint,
+        Transform::from_scale(scale, scale),
+        None,
+    );
+    Ok(pixmap)
+}
+
+async fn load_icons<F: Fetcher>(
+    paths: &[String],
+    pixel_ratio: u32,
+    client: &F,
+) -> Result<Vec<IconData>, APIError> {
+    let mut sources: BTreeMap<String, IconSource> = BTreeMap::new();
+    for path in paths {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        if let Some(name) = file_name.strip_suffix(".svg") {
+            sources.entry(name.to_string()).or_default().svg = Some(path.clone());
+        } else if let Some(name) = file_name.strip_suffix("@2x.png") {
+            sources.entry(name.to_string()).or_default().png_2x = Some(path.clone());
+        } else if let Some(name) = file_name.strip_suffix(".png") {
+            sources.entry(name.to_string()).or_default().png = Some(path.clone());
+        }
+    }
+    if sources.is_empty() {
+        return Err(APIError::NotFound(Some("no icons found".into())));
+    }
+
+    let ratio = pixel_ratio as f32;
+    let mut icons = Vec::with_capacity(sources.len());
+    for (name, source) in sources {
+        // Prefer vector sources and otherwise the PNG closest to the requested ratio
+        let (path, is_svg, scale) = match source {
+            IconSource { svg: Some(svg), .. } => (svg, true, ratio),
+            IconSource {
+                png_2x: Some(png_2x),
+                ..
+            } if pixel_ratio >= 2 || source.png.is_none() => (png_2x, false, ratio / 2.),
+            IconSource { png: Some(png), .. } => (png, false, ratio),
+            _ => continue,
+        };
+        let (data, _) = client.get_data(&path).await?;
+        icons.push(IconData {
+            name,
+            path,
+            data,
+            is_svg,
+            scale,
+        });
+    }
+    Ok(icons)
+}
+
+fn render_icons(sources: Vec<IconData>) -> Result<Vec<Icon>, APIError> {
+    sources
+        .into_iter()
+        .map(|source| {
+            let pixmap = if source.is_svg {
+                render_svg(&source.data, source.scale)
+            } else {
+                render_png(&source.data, source.scale)
+            }
+            .map_err(|err| {
+                tracing::error!("unable to render icon {}: {}", source.path, err);
+                APIError::Internal("unable to render icon".into())
+            })?;
+            Ok(Icon {
+                name: source.name,
+                pixmap,
+            })
+        })
+        .collect()
+}
+
+// Pack icons into rows ordered by height, aiming at a roughly square sprite sheet
+fn pack_icons(icons: &mut [Icon]) -> (u32, u32, Vec<(u32, u32)>) {
+    icons.sort_by(|a, b| {
+        b.pixmap
+            .height()
+            .cmp(&a.pixmap.height())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let area: u64 = icons
+        .iter()
+        .map(|icon| icon.pixmap.width() as u64 * icon.pixmap.height() as u64)
+        .sum();
+    let widest = icons.iter().map(|icon| icon.pixmap.width()).max();
+    let target_width = ((area as f64).sqrt().ceil() as u32).max(widest.unwrap_or(1));
+
+    let mut positions = Vec::with_capacity(icons.len());
+    let (mut x, mut y, mut row_height, mut width) = (0, 0, 0, 0);
+    for icon in icons.iter() {
+        if x > 0 && x + icon.pixmap.width() > target_width {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+        positions.push((x, y));
+        x += icon.pixmap.width();
+        width = width.max(x);
+        row_height = row_height.max(icon.pixmap.height());
+    }
+    (width.max(1), (y + row_height).max(1), positions)
+}
+
+// Render the icons and pack them into a sprite index and image
+fn render_sprite(sources: Vec<IconData>, pixel_ratio: u32) -> Result<(Bytes, Bytes), APIError> {
+    let mut icons = render_icons(sources)?;
+    let (width, height, positions) = pack_icons(&mut icons);
+
+    let mut sheet = Pixmap::new(width, height)
+        .ok_or_else(|| APIError::Internal("invalid sprite size".into()))?;
+    let mut index = BTreeMap::new();
+    for (icon, (x, y)) in icons.iter().zip(positions) {
+        sheet.draw_pixmap(
+            x as i32,
+            y as i32,
+            icon.pixmap.as_ref(),
+            &PixmapPaint::default(),
+            Transform::identity(),
+            None,
+        );
+        index.insert(
+            icon.name.clone(),
+            SpriteIcon {
+                x,
+                y,
+                width: icon.pixmap.width(),
+                height: icon.pixmap.height(),
+                pixel_ratio,
+            },
+        );
+    }
+
+    let index_json = serde_json::to_vec(&index).map_err(|err| {
+        tracing::error!("{}", err);
+        APIError::Internal("unable to serialize sprite index".into())
+    })?;
+    let image_png = sheet.encode_png().map_err(|err| {
+        tracing::error!("{}", err);
+        APIError::Internal("unable to encode sprite image".into())
+    })?;
+    Ok((index_json.into(), image_png.into()))
+}
+
+// Build a sprite index and image from the given icon files. Rendering runs on a blocking
+// thread as large SVG icons can take a while to rasterize.
+pub async fn build_sprite<F: Fetcher>(
+    paths: &[String],
+    pixel_ratio: u32,
+    client: &F,
+) -> Result<(Bytes, Bytes), APIError> {
+    let sources = load_icons(paths, pixel_ratio, client).await?;
+    tokio::task::spawn_blocking(move || render_sprite(sources, pixel_ratio))
+        .await
+        .map_err(|err| APIError::Internal(err.to_string()))?
+}
+
+// Hash of the icon file names, so that sprites are rebuilt once icons are added or removed
+fn listing_hash(paths: &mut [String]) -> u64 {
+    paths.sort();
+    xxh3_64(paths.join("\n").as_bytes())
+}
+
+pub async fn fetch_generated_sprite<F: Fetcher, C: Cache + ?Sized>(
+    icons_path: &str,
+    pixel_ratio: u32,
+    extension: &str,
+    client: &F,
+    cache: Option<&C>,
+    builds: &SpriteBuilds,
+) -> Result<Bytes, APIError> {
+    let mut paths = client.list_paths(icons_path).await?;
+    let sprite_key = format!(
+        "{}|sprite@{}x|{:016x}",
+        icons_path,
+        pixel_ratio,
+        listing_hash(&mut paths)
+    );
+    let index_key = format!("{}.json", sprite_key);
+    let image_key = format!("{}.png", sprite_key);
+    let key = if extension == "json" {
+        &index_key
+    } else {
+        &image_key
+    };
+    let select = |(index_json, image_png): (Bytes, Bytes)| {
+        if extension == "json" {
+            index_json
+        } else {
+            image_png
+        }
+    };
+    if let Some(cached) = cache.and_then(|cache| cache.get(key)) {
+        tracing::debug!("cache hit for key {}", key);
+        return Ok(cached);
+    }
+
+    let shared = builds.entry(&sprite_key);
+    let mut built = shared.lock().await;
+    if let Some(sprite) = built.as_ref() {
+        tracing::debug!("reusing sprite built for key {}", sprite_key);
+        return Ok(select(sprite.clone()));
+    }
+    tracing::info!("generating sprite @{}x from {}", pixel_ratio, icons_path);
+    let res = build_sprite(&paths, pixel_ratio, client).await;
+    if let (Ok((index_json, image_png)), Some(cache)) = (&res, cache) {
+        for (key, data) in [(&index_key, index_json), (&image_key, image_png)] {
+            let res = cache.set(key, data.clone());
+            if let Err(err) = res {
+                tracing::warn!("failed to cache key {} with {}", key, err);
+            } else {
+                tracing::debug!("cached key {}", key);
+            }
+        }
+    };
+    if let Ok(sprite) = &res {
+        *built = Some(sprite.clone());
+    }
+    // Callers arriving from now on find the sprite in the cache or build it anew
+    builds.remove(&sprite_key);
+    drop(built);
+    res.map(select)
+}
+
+#[cfg(test)]
+use pmtiles_core::cache::InMemoryCache;
+#[cfg(test)]
+use pmtiles_core::fetcher::LocalFetcher;
+
+#[tokio::test]
+async fn test_fetch_sprite() {
+    let client = LocalFetcher::new();
+    let cache = InMemoryCache::new();
+    let path = "../../testdata/sprites/sprite.json";
+    let data = fetch_sprite(path, &client, Some(&cache)).await.unwrap();
+    let index: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    assert!(index.get("marker").is_some());
+    assert_eq!(cache.get(path).unwrap(), data);
+
+    assert_eq!(sprite_content_type("sprite@2x.png"), Some("image/png"));
+    assert_eq!(sprite_content_type("sprite.json"), Some("application/json"));
+    assert_eq!(sprite_content_type("../sprite.json"), None);
+    assert_eq!(sprite_content_type("sprite.svg"), None);
+}
+
+#[tokio::test]
+async fn test_generate_sprite() {
+    let client = LocalFetcher::new();
+    let cache = InMemoryCache::new();
+    let icons_path = "../../testdata/icons/basic";
+
+    let builds = SpriteBuilds::new();
+
+    let index = fetch_generated_sprite(icons_path, 1, "json", &client, Some(&cache), &builds)
+        .await
+        .unwrap();
+    let index: serde_json::Value = serde_json::from_slice(&index).unwrap();
+    assert_eq!(index["marker"]["width"], 16);
+    assert_eq!(index["square"]["width"], 24);
+    assert_eq!(index["square"]["height"], 12);
+    assert_eq!(index["square"]["pixelRatio"], 1);
+
+    // Concurrent requests for the same sprite share a single build
+    let (image, other) = tokio::join!(
+        fetch_generated_sprite(icons_path, 2, "png", &client, Some(&cache), &builds),
+        fetch_generated_sprite(icons_path, 2, "png", &client, Some(&cache), &builds),
+    );
+    let image = image.unwrap();
+    assert_eq!(other.unwrap(), image);
+    assert!(builds.building.lock().unwrap().is_empty());
+    let sheet = Pixmap::decode_png(&image).unwrap();
+    assert!(sheet.width() >= 48 && sheet.height() >= 32);
+
+    // Sprites are cached under a hash of the icon listing
+    let mut paths = client.list_paths(icons_path).await.unwrap();
+    let hash = listing_hash(&mut paths);
+    let index = cache
+        .get(&format!("{}|sprite@2x|{:016x}.json", icons_path, hash))
+        .unwrap();
+    let index: serde_json::Value = serde_json::from_slice(&index).unwrap();
+    assert_eq!(index["marker"]["width"], 32);
+    assert_eq!(index["square"]["height"], 24);
+    assert_eq!(index["square"]["pixelRatio"], 2);
+
+    assert_eq!(parse_sprite_name("basic@2x.png"), Some(("basic", 2, "png")));
+    assert_eq!(
+        parse_sprite_name("nested/basic.json"),
+        Some(("nested/basic", 1, "json"))
+    );
+    assert_eq!(parse_sprite_name("basic@9x.png"), None);
+}
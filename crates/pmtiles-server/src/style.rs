@@ -2,10 +2,10 @@ use crate::{
     config::{get_data_path, get_path, prefix_with_home, ServerConfig},
     utils::join_path,
 };
-use pmtiles_core::models::Headers;
+use pmtiles_core::models::{Headers, TileType};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct StyleSource {
@@ -22,7 +22,7 @@ pub struct Style {
     pub zoom: u8,
     pub bearing: u8,
     pub pitch: u8,
-    pub sources: HashMap<String, StyleSource>,
+    pub sources: BTreeMap<String, StyleSource>,
     pub sprite: String,
     pub glyphs: String,
     pub layers: serde_json::Value,
@@ -34,11 +34,11 @@ impl Style {
 
         let mut resolved = self.to_owned();
 
-        let resolved_sources: HashMap<_, _> = resolved
+        let resolved_sources: BTreeMap<_, _> = resolved
             .sources
             .into_iter()
             .map(|(key, mut src)| {
-                let path = get_data_path(&key, &src.url, &config);
+                let path = get_data_path(&key, &src.url, config);
                 src.url = format!("{}/{}", &domain, &path);
                 (key, src)
             })
@@ -46,7 +46,7 @@ impl Style {
         resolved.sources = resolved_sources;
 
         if !resolved.glyphs.is_empty() {
-            let path = get_path(&resolved.glyphs, &config);
+            let path = get_path(&resolved.glyphs, config);
             resolved.glyphs = format!(
                 "{}/{}",
                 &domain.trim_end_matches("/"),
@@ -55,7 +55,7 @@ impl Style {
         };
 
         if !resolved.sprite.is_empty() {
-            let path = get_path(&resolved.sprite, &config);
+            let path = get_path(&resolved.sprite, config);
             resolved.sprite = format!(
                 "{}/{}",
                 &domain.trim_end_matches("/"),
@@ -70,7 +70,7 @@ impl Style {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VectorLayer {
     id: String,
-    fields: Option<HashMap<String, String>>,
+    fields: Option<BTreeMap<String, String>>,
     minzoom: Option<u64>,
     maxzoom: Option<u64>,
     description: Option<String>,
@@ -84,7 +84,11 @@ pub struct TileSource {
     center: Option<Vec<f64>>,
     maxzoom: Option<i64>,
     minzoom: Option<i64>,
-    vector_layers: Vec<VectorLayer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    // Only vector tilesets describe their layers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vector_layers: Option<Vec<VectorLayer>>,
 }
 
 impl TileSource {
@@ -94,38 +98,40 @@ impl TileSource {
         metadata: &serde_json::Value,
         cfg: &ServerConfig,
     ) -> Result<Self, anyhow::Error> {
-        let name = metadata
-            .get("name")
-            .unwrap_or_else(|| &Value::Null)
-            .as_str()
-            .clone();
+        let name = metadata.get("name").unwrap_or(&Value::Null).as_str();
 
         let minzoom = metadata
             .get("minzoom")
-            .unwrap_or_else(|| &Value::Null)
+            .unwrap_or(&Value::Null)
             .as_str()
-            .clone()
-            .unwrap_or_else(|| "")
+            .unwrap_or("")
             .parse::<i64>()
             .ok();
         let maxzoom = metadata
             .get("maxzoom")
-            .unwrap_or_else(|| &Value::Null)
+            .unwrap_or(&Value::Null)
             .as_str()
-            .clone()
-            .unwrap_or_else(|| "")
+            .unwrap_or("")
             .parse::<i64>()
             .ok();
 
-        let vector_layers: Vec<VectorLayer> = serde_json::from_value(
-            metadata
-                .get("vector_layers")
-                .unwrap_or_else(|| &Value::Null)
-                .clone(),
-        )
-        .unwrap_or_else(|_| vec![]);
+        let tile_type = TileType::from(headers.tile_type);
+        let vector_layers: Option<Vec<VectorLayer>> = if tile_type.is_raster() {
+            None
+        } else {
+            Some(
+                serde_json::from_value(
+                    metadata
+                        .get("vector_layers")
+                        .unwrap_or(&Value::Null)
+                        .clone(),
+                )
+                .unwrap_or_else(|_| vec![]),
+            )
+        };
 
-        let tile_urls = vec![format!("{}/{{z}}/{{x}}/{{y}}.pbf", tileset)]
+        let extension = tile_type.extension().unwrap_or("pbf");
+        let tile_urls = vec![format!("{}/{{z}}/{{x}}/{{y}}.{}", tileset, extension)]
             .into_iter()
             .map(|mut x| {
                 prefix_with_home(&mut x, cfg, true, true);
@@ -137,6 +143,7 @@ impl TileSource {
             tilejson: "3.0.0".into(),
             name: name.map(ToOwned::to_owned),
             tiles: tile_urls,
+            format: tile_type.extension().map(Into::into),
             maxzoom,
             minzoom,
             center: Some(vec![
@@ -206,3 +213,37 @@ fn test_style_render() {
         expected.sources.get("cadastral_fi").unwrap()
     );
 }
+
+#[test]
+fn test_raster_tilejson() {
+    let config_path = std::path::Path::new("../../testdata/styles/config.json")
+        .canonicalize()
+        .unwrap();
+    let config_data = std::fs::read_to_string(config_path).unwrap();
+    let config: ServerConfig = serde_json::from_str(&config_data).unwrap();
+
+    let headers = Headers {
+        tile_type: TileType::Webp.into(),
+        ..Default::default()
+    };
+    let metadata = serde_json::json!({"name": "orthophoto", "minzoom": "0", "maxzoom": "12"});
+    let tilejson =
+        TileSource::try_from_headers_and_metadata("ortho", &headers, &metadata, &config).unwrap();
+    let tilejson = serde_json::to_value(tilejson).unwrap();
+    assert_eq!(
+        tilejson["tiles"][0],
+        "http://api.example.com/tile/ortho/{z}/{x}/{y}.webp"
+    );
+    assert_eq!(tilejson["format"], "webp");
+    assert!(tilejson.get("vector_layers").is_none());
+
+    let headers = Headers {
+        tile_type: TileType::Mvt.into(),
+        ..Default::default()
+    };
+    let tilejson =
+        TileSource::try_from_headers_and_metadata("ortho", &headers, &metadata, &config).unwrap();
+    let tilejson = serde_json::to_value(tilejson).unwrap();
+    assert_eq!(tilejson["format"], "pbf");
+    assert_eq!(tilejson["vector_layers"], serde_json::json!([]));
+}
@@ -0,0 +1,163 @@
+use crate::error::APIError;
+use pmtiles_core::models::TileType;
+
+// Deepest zoom level a tile id can address
+const MAX_ZOOM: u64 = 26;
+
+// How the y coordinate of a tile url is counted: XYZ from the north, TMS from the south
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileScheme {
+    Xyz,
+    Tms,
+}
+
+// Coordinates of a requested tile, with y always in the XYZ scheme
+#[derive(Debug, PartialEq)]
+pub struct TileRequest {
+    pub z: u64,
+    pub x: u64,
+    pub y: u64,
+    // Whether the high-DPI variant was requested with an @2x suffix. Archives hold a single
+    // resolution, so raster tiles are served as stored either way.
+    pub retina: bool,
+    pub extension: Option<String>,
+}
+
+fn bad_request(message: &str) -> APIError {
+    APIError::BadRequest(Some(message.into()))
+}
+
+impl TileRequest {
+    // Parse the z, x and y segments of a tile url. The y segment may carry an @2x suffix
+    // and any extension, e.g. 4732.pbf or 4732@2x.png.
+    pub fn parse(z: &str, x: &str, y: &str, scheme: TileScheme) -> Result<Self, APIError> {
+        let (y, extension) = match y.rsplit_once('.') {
+            Some((y, extension)) => (y, Some(extension.to_string())),
+            None => (y, None),
+        };
+        let (y, retina) = match y.strip_suffix("@2x") {
+            Some(y) => (y, true),
+            None => (y, false),
+        };
+        let z = z
+            .parse::<u64>()
+            .map_err(|_| bad_request("zoom level must be a non-negative integer"))?;
+        let x = x
+            .parse::<u64>()
+            .map_err(|_| bad_request("x must be a non-negative integer"))?;
+        let y = y
+            .parse::<u64>()
+            .map_err(|_| bad_request("y must be a non-negative integer"))?;
+        if z > MAX_ZOOM {
+            return Err(bad_request(&format!(
+                "zoom level must be at most {}",
+                MAX_ZOOM
+            )));
+        }
+        let max = (1_u64 << z) - 1;
+        if x > max || y > max {
+            return Err(bad_request(&format!(
+                "x and y must be at most {} at zoom level {}",
+                max, z
+            )));
+        }
+        let y = match scheme {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => max - y,
+        };
+        Ok(TileRequest {
+            z,
+            x,
+            y,
+            retina,
+            extension,
+        })
+    }
+
+    // Vector tiles have no high-DPI variant. For raster tiles @2x is an alias of the
+    // stored tile, which is not advertised in TileJSON.
+    pub fn check_retina(&self, tile_type: TileType) -> Result<(), APIError> {
+        if self.retina && !tile_type.is_raster() {
+            return Err(bad_request(
+                "@2x tiles are only available for raster tilesets",
+            ));
+        }
+        Ok(())
+    }
+
+    // Check the extension of the url against the tile type of the tileset. Urls without
+    // an extension, and tilesets of unknown tile types, accept any tile.
+    pub fn check_extension(&self, tile_type: TileType) -> Result<(), APIError> {
+        match (&self.extension, tile_type.extension()) {
+            (Some(extension), Some(expected)) if TileType::from_format(extension) != tile_type => {
+                Err(APIError::NotFound(Some(format!(
+                    "tiles of this tileset are .{} files",
+                    expected
+                ))))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_parse_tile_request() {
+    assert_eq!(
+        TileRequest::parse("14", "9325", "4732.pbf", TileScheme::Xyz).unwrap(),
+        TileRequest {
+            z: 14,
+            x: 9325,
+            y: 4732,
+            retina: false,
+            extension: Some("pbf".into())
+        }
+    );
+    assert_eq!(
+        TileRequest::parse("2", "1", "3", TileScheme::Xyz).unwrap(),
+        TileRequest {
+            z: 2,
+            x: 1,
+            y: 3,
+            retina: false,
+            extension: None
+        }
+    );
+    assert_eq!(
+        TileRequest::parse("2", "1", "0@2x.png", TileScheme::Tms).unwrap(),
+        TileRequest {
+            z: 2,
+            x: 1,
+            y: 3,
+            retina: true,
+            extension: Some("png".into())
+        }
+    );
+
+    let invalid = [
+        ("a", "0", "0.pbf"),
+        ("1", "-1", "0.pbf"),
+        ("1", "0", "0@3x.png"),
+        ("1", "0", "2.pbf"),
+        ("27", "0", "0.pbf"),
+    ];
+    for (z, x, y) in invalid {
+        let res = TileRequest::parse(z, x, y, TileScheme::Xyz);
+        assert!(matches!(res, Err(APIError::BadRequest(_))), "{z}/{x}/{y}");
+    }
+
+    let check = |y: &str, tile_type: TileType| {
+        TileRequest::parse("1", "1", y, TileScheme::Xyz)
+            .unwrap()
+            .check_extension(tile_type)
+    };
+    assert!(check("1.pbf", TileType::Mvt).is_ok());
+    assert!(check("1.mvt", TileType::Mvt).is_ok());
+    assert!(check("1.jpeg", TileType::Jpeg).is_ok());
+    assert!(check("1", TileType::Png).is_ok());
+    assert!(check("1.foo", TileType::Unknown).is_ok());
+    assert!(matches!(
+        check("1.pbf", TileType::Png),
+        Err(APIError::NotFound(_))
+    ));
+    assert!(check("1.foo", TileType::Mvt).is_err());
+}
@@ -1,12 +1,14 @@
+use axum::http::{header, HeaderMap};
+use pmtiles_core::httputils::is_http_path;
 use pmtiles_core::s3utils::is_s3_path;
 use rand::Rng;
 
 pub fn canonicalize_local_path(path: &str) -> anyhow::Result<String> {
-    if is_s3_path(path) {
+    if is_s3_path(path) || is_http_path(path) {
         Ok(path.into())
     } else {
         let ref_path = {
-            if path == "" {
+            if path.is_empty() {
                 "."
             } else {
                 path
@@ -35,5 +37,46 @@ pub fn join_path(part1: &str, part2: &str) -> String {
 }
 
 pub fn trim_slash(path: &str) -> String {
-    format!("{}", path.trim_matches('/'))
+    path.trim_matches('/').to_string()
+}
+
+// Check whether the Accept-Encoding request header allows the given content coding
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let mut explicit = None;
+    let mut wildcard = None;
+    let codings = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            explicit = Some(q);
+        } else if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    explicit.or(wildcard).is_some_and(|q| q > 0.0)
+}
+
+#[test]
+fn test_accepts_encoding() {
+    let mut headers = HeaderMap::new();
+    assert!(!accepts_encoding(&headers, "gzip"));
+    headers.insert(
+        header::ACCEPT_ENCODING,
+        "gzip, deflate, br;q=0.5".parse().unwrap(),
+    );
+    assert!(accepts_encoding(&headers, "gzip"));
+    assert!(accepts_encoding(&headers, "br"));
+    assert!(!accepts_encoding(&headers, "zstd"));
+    headers.insert(header::ACCEPT_ENCODING, "*, gzip;q=0".parse().unwrap());
+    assert!(!accepts_encoding(&headers, "gzip"));
+    assert!(accepts_encoding(&headers, "zstd"));
 }